@@ -0,0 +1,300 @@
+use std::fmt;
+use std::path::Path;
+
+use crate::error::{Error, ErrorKind, Phase};
+
+use crate::key::{escape_bytes, Fingerprint};
+use crate::token::TokenReader;
+use crate::v1::io::{ChunkError, Location, ReadOptions};
+
+pub const DEFAULT_SKETCH_WIDTH: usize = 1 << 26;
+pub const DEFAULT_SKETCH_DEPTH: usize = 4;
+pub const DEFAULT_CANDIDATES: usize = 1 << 20;
+
+/// count-min sketch with saturating 8-bit cells and conservative update
+///
+/// only 0, 1 and "more" matter for unique words, so a byte per cell is enough.
+pub struct CountMinSketch {
+    width: usize,
+    depth: usize,
+    cells: Vec<u8>,
+}
+
+impl CountMinSketch {
+    pub fn new(width: usize, depth: usize) -> Self {
+        let width = width.max(1);
+        let depth = depth.max(1);
+
+        CountMinSketch {
+            width,
+            depth,
+            cells: vec![0; width * depth],
+        }
+    }
+
+    /// cell of `row`, rows are derived by double hashing the fingerprint
+    fn index(&self, fingerprint: Fingerprint, row: usize) -> usize {
+        let h = fingerprint.0.wrapping_add((row as u64).wrapping_mul(fingerprint.1));
+
+        row * self.width + (h % self.width as u64) as usize
+    }
+
+    /// count once, return the estimate after update
+    pub fn add(&mut self, fingerprint: Fingerprint) -> u8 {
+        let estimate = self.estimate(fingerprint);
+        let next = estimate.saturating_add(1);
+
+        // conservative update: only raise the cells holding the minimum
+        for row in 0..self.depth {
+            let idx = self.index(fingerprint, row);
+
+            if self.cells[idx] == estimate {
+                self.cells[idx] = next;
+            }
+        }
+
+        next
+    }
+
+    /// never less than the true count
+    pub fn estimate(&self, fingerprint: Fingerprint) -> u8 {
+        (0..self.depth)
+            .map(|row| self.cells[self.index(fingerprint, row)])
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// estimated probability that a word seen once still estimates more than
+    /// once, i.e. every row shares its cell with some other word
+    ///
+    /// it's the product of each row's occupancy, which takes the rows as
+    /// independent, so it's an estimate and not a bound.
+    pub fn miss_probability(&self) -> f64 {
+        self.cells
+            .chunks(self.width)
+            .map(|row| row.iter().filter(|&&c| c != 0).count() as f64 / self.width as f64)
+            .product()
+    }
+}
+
+/// answer of the approximate mode
+#[derive(Debug)]
+pub struct Estimate {
+    /// a word reported here is certainly unique, it might not be the first one
+    pub answer: Option<(String, Location)>,
+    /// estimated chance that an earlier unique word was missed, 1 when
+    /// nothing is found and singletons were dropped
+    pub miss_probability: f64,
+    /// singletons dropped because candidate list was full
+    pub dropped: u64,
+}
+
+impl fmt::Display for Estimate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.answer {
+            Some((word, location)) => write!(f, "probably first unique word {:?} at {}", word, location)?,
+            None => write!(f, "no unique word found")?,
+        }
+
+        write!(f, ", each earlier unique word missed with estimated probability {:.6}", self.miss_probability)?;
+
+        match (&self.answer, self.dropped) {
+            (_, 0) => {}
+            (Some(_), dropped) => write!(f, ", {} later singletons dropped", dropped)?,
+            // the first unique word may be one of them
+            (None, dropped) => write!(f, ", {} singletons dropped, raise --candidates", dropped)?,
+        }
+
+        Ok(())
+    }
+}
+
+/// ApproxCounter keeps a sketch of all words and a bounded list of the
+/// earliest words seen once, no temp file is written.
+pub struct ApproxCounter {
+    sketch: CountMinSketch,
+
+    /// candidates in source order
+    candidates: Vec<(Vec<u8>, Location, Fingerprint)>,
+    capacity: usize,
+
+    /// first sightings counted, and when a compaction is allowed again
+    seen: u64,
+    next_compact: u64,
+    dropped: u64,
+}
+
+impl ApproxCounter {
+    pub fn new(width: usize, depth: usize, capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+
+        ApproxCounter {
+            sketch: CountMinSketch::new(width, depth),
+            candidates: Vec::with_capacity(capacity),
+            capacity,
+            seen: 0,
+            next_compact: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn count(&mut self, word: Vec<u8>, location: Location) {
+        let fingerprint = Fingerprint::of(&word);
+
+        if self.sketch.add(fingerprint) != 1 {
+            return;
+        }
+
+        self.seen += 1;
+
+        if self.candidates.len() >= self.capacity && self.seen >= self.next_compact {
+            self.compact();
+        }
+
+        // once one is dropped, a later one must not take the room compaction
+        // frees, or it's found before the dropped one
+        if self.candidates.len() < self.capacity && self.dropped == 0 {
+            self.candidates.push((word, location, fingerprint));
+        } else {
+            self.dropped += 1;
+        }
+    }
+
+    /// drop candidates seen again, at most once per half capacity first
+    /// sightings to keep it amortized
+    fn compact(&mut self) {
+        let sketch = &self.sketch;
+
+        self.candidates.retain(|it| sketch.estimate(it.2) == 1);
+        self.next_compact = self.seen + self.capacity as u64 / 2;
+    }
+
+    pub fn finish(self) -> Estimate {
+        let sketch = &self.sketch;
+
+        let answer = self.candidates
+            .into_iter()
+            .find(|it| sketch.estimate(it.2) == 1)
+            .map(|it| (escape_bytes(&it.0), it.1));
+
+        // candidates are all earlier than the dropped singletons, without an
+        // answer among them the first unique word is unknown
+        let miss_probability = if answer.is_none() && self.dropped > 0 {
+            1.0
+        } else {
+            sketch.miss_probability()
+        };
+
+        Estimate {
+            answer,
+            miss_probability,
+            dropped: self.dropped,
+        }
+    }
+}
+
+/// scan the source once with a sketch of `width * depth` bytes
+pub fn solve<P: AsRef<Path>>(
+    path: P,
+    options: &ReadOptions,
+    width: usize,
+    depth: usize,
+    capacity: usize,
+) -> Result<Estimate, Error> {
+    let mut io = TokenReader::open(path, options)?;
+    let mut counter = ApproxCounter::new(width, depth, capacity);
+
+    loop {
+        match io.next_token() {
+            Ok((word, location)) => {
+                counter.count(word, location);
+            }
+
+            Err(ChunkError::NextChunk) => {
+                io.load_chunk()?;
+            }
+
+            Err(ChunkError::Eof) => {
+                break;
+            }
+
+            Err(ChunkError::Cancelled(location)) => {
+                return Err(Error::new(ErrorKind::Cancelled).with_offset(location.offset).with_phase(Phase::Read))
+            }
+
+            Err(ChunkError::Fatal(err)) => return Err(err),
+        }
+    }
+
+    Ok(counter.finish())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ApproxCounter, CountMinSketch};
+    use crate::key::Fingerprint;
+    use crate::v1::io::Location;
+
+    #[test]
+    fn test_sketch() {
+        let mut sketch = CountMinSketch::new(1024, 4);
+
+        assert_eq!(sketch.add(Fingerprint::of(b"qwer")), 1);
+        assert_eq!(sketch.add(Fingerprint::of(b"qwer")), 2);
+        assert_eq!(sketch.add(Fingerprint::of(b"abcd")), 1);
+
+        assert_eq!(sketch.estimate(Fingerprint::of(b"qwer")), 2);
+        assert!(sketch.miss_probability() < 0.01);
+
+        // every row counts, not only the first one
+        let mut sketch = CountMinSketch::new(2, 2);
+        sketch.cells[0] = 1;
+        assert_eq!(sketch.miss_probability(), 0.0);
+
+        sketch.cells[2] = 1;
+        assert_eq!(sketch.miss_probability(), 0.25);
+    }
+
+    #[test]
+    fn test_counter() {
+        let mut counter = ApproxCounter::new(1024, 4, 16);
+
+        for (idx, word) in ["qwer", "abcd", "qwer", "zxcv", "abcd"].iter().enumerate() {
+            counter.count(word.as_bytes().to_vec(), Location::new(idx as u64 * 5, idx as u64 + 1));
+        }
+
+        let estimate = counter.finish();
+
+        assert_eq!(estimate.answer, Some(("zxcv".into(), Location::new(15, 4))));
+        assert_eq!(estimate.dropped, 0);
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut counter = ApproxCounter::new(1024, 4, 2);
+
+        // "qwer" and "abcd" repeat, compaction makes room for "zxcv"
+        for (idx, word) in ["qwer", "abcd", "qwer", "abcd", "zxcv"].iter().enumerate() {
+            counter.count(word.as_bytes().to_vec(), Location::new(idx as u64 * 5, idx as u64 + 1));
+        }
+
+        assert_eq!(counter.finish().answer, Some(("zxcv".into(), Location::new(20, 5))));
+    }
+
+    #[test]
+    fn test_dropped() {
+        let mut counter = ApproxCounter::new(1024, 4, 2);
+
+        // "c" is dropped, "d" must not take the room freed by "a" and "b"
+        for (idx, word) in ["a", "b", "c", "a", "b", "d"].iter().enumerate() {
+            counter.count(word.as_bytes().to_vec(), Location::new(idx as u64 * 2, idx as u64 + 1));
+        }
+
+        let estimate = counter.finish();
+
+        assert_eq!(estimate.answer, None);
+        assert_eq!(estimate.dropped, 2);
+        assert_eq!(estimate.miss_probability, 1.0);
+        assert!(estimate.to_string().contains("raise --candidates"), "{}", estimate);
+    }
+}
@@ -0,0 +1,84 @@
+use std::io;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::error::{Error, ErrorKind};
+
+/// set by SIGINT or SIGTERM, seen by every token
+static SIGNALED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(_signal: libc::c_int) {
+    SIGNALED.store(true, Ordering::SeqCst);
+}
+
+/// cancel every token on SIGINT or SIGTERM, a second signal kills the process
+///
+/// nothing is left to clean up then, temp files are unlinked when created
+/// and only named files of a state directory outlive the process.
+pub fn install_handler() -> io::Result<()> {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        let mut action: libc::sigaction = unsafe { mem::zeroed() };
+        action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESETHAND;
+
+        if unsafe { libc::sigaction(signal, &action, ptr::null_mut()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// CancelToken asks a long running step to stop at its next safe point.
+///
+/// steps check it between words, partitions and merged records, so a
+/// checkpoint and temp files are left consistent.
+#[derive(Clone, Default, Debug)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    /// signals go through [install_handler], this cancels one token only
+    #[cfg(test)]
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed) || SIGNALED.load(Ordering::Relaxed)
+    }
+
+    /// `Err` once cancelled
+    pub fn check(&self) -> Result<(), Error> {
+        if self.is_cancelled() {
+            return Err(Error::new(ErrorKind::Cancelled));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::CancelToken;
+
+    #[test]
+    fn test_cancel() {
+        let token = CancelToken::new();
+        let other = token.clone();
+
+        assert!(token.check().is_ok());
+
+        other.cancel();
+        assert!(token.is_cancelled());
+        assert!(token.check().unwrap_err().is_cancelled());
+
+        // tokens are independent unless cloned
+        assert!(!CancelToken::new().is_cancelled());
+    }
+}
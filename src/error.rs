@@ -0,0 +1,257 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// stage of the pipeline an error happens in
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Phase {
+    /// reading the source file
+    Read,
+    /// writing hash partitions
+    Split,
+    /// counting a partition, or flushing a sorted run
+    Count,
+    /// merging sorted runs
+    Merge,
+    /// reading the answer back from source
+    Recover,
+    /// choosing partitions, buffers and limits before the scan
+    Plan,
+    /// saving or loading a checkpoint
+    Checkpoint,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Phase::Read => "read",
+            Phase::Split => "split",
+            Phase::Count => "count",
+            Phase::Merge => "merge",
+            Phase::Recover => "recover",
+            Phase::Plan => "plan",
+            Phase::Checkpoint => "checkpoint",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug)]
+pub enum ErrorKind {
+    Io(io::Error),
+    Encode(bincode::Error),
+    InvalidUtf8,
+    CorruptTempFile(&'static str),
+    FingerprintMismatch,
+    InvalidArgument(String),
+    /// estimated temp bytes, and bytes available
+    InsufficientTempSpace(u64, u64),
+    /// `--max-temp-bytes` it's over
+    TempQuotaExceeded(u64),
+    /// stopped by a signal or a cancelled token
+    Cancelled,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::Io(err) => write!(f, "{}", err),
+            ErrorKind::Encode(err) => write!(f, "temp file encoding: {}", err),
+            ErrorKind::InvalidUtf8 => write!(f, "invalid UTF-8"),
+            ErrorKind::CorruptTempFile(reason) => write!(f, "corrupt temp file, {}", reason),
+            ErrorKind::FingerprintMismatch => write!(f, "word read back doesn't match its fingerprint"),
+            ErrorKind::InvalidArgument(msg) => write!(f, "invalid argument: {}", msg),
+            ErrorKind::InsufficientTempSpace(needed, available) => {
+                write!(f, "temp files need about {} bytes, only {} available", needed, available)
+            }
+            ErrorKind::TempQuotaExceeded(quota) => write!(f, "temp files exceed --max-temp-bytes {}", quota),
+            ErrorKind::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+/// Error with the context it happens in
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+
+    phase: Option<Phase>,
+    file: Option<PathBuf>,
+    offset: Option<u64>,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn new(kind: ErrorKind) -> Self {
+        Error {
+            kind,
+            phase: None,
+            file: None,
+            offset: None,
+        }
+    }
+
+    /// inner context is kept, it's closer to where the error happens
+    pub fn with_phase(mut self, phase: Phase) -> Self {
+        self.phase.get_or_insert(phase);
+        self
+    }
+
+    pub fn with_file<P: AsRef<Path>>(mut self, file: P) -> Self {
+        self.file.get_or_insert_with(|| file.as_ref().to_owned());
+        self
+    }
+
+    pub fn with_offset(mut self, offset: u64) -> Self {
+        self.offset.get_or_insert(offset);
+        self
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        matches!(self.kind, ErrorKind::Cancelled)
+    }
+
+    /// what the user can do about it, if anything
+    fn hint(&self) -> Option<&'static str> {
+        match &self.kind {
+            ErrorKind::Io(err) if err.raw_os_error() == Some(libc::ENOSPC) => {
+                Some("temp files are written to --state-dir if given, otherwise to TMPDIR, free some space there or move it to a larger disk")
+            }
+            // only a merge opens more files for a higher fan-in
+            ErrorKind::Io(err) if err.raw_os_error() == Some(libc::EMFILE) => match self.phase {
+                Some(Phase::Merge) => Some("too many open files, raise `ulimit -n` or lower --max-fan-in"),
+                _ => Some("too many open files, raise `ulimit -n`, or a larger --memory makes fewer v2 partitions"),
+            },
+            ErrorKind::InvalidUtf8 => Some("input must be UTF-8 text, or pass --encoding lossy or bytes"),
+            ErrorKind::InsufficientTempSpace(..) | ErrorKind::TempQuotaExceeded(_) => {
+                Some("--fingerprint writes smaller temp files, --strategy approx writes none")
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(phase) = self.phase {
+            write!(f, "{} failed: ", phase)?;
+        }
+
+        write!(f, "{}", self.kind)?;
+
+        if let Some(file) = &self.file {
+            write!(f, " in {}", file.display())?;
+        }
+
+        if let Some(offset) = self.offset {
+            write!(f, " at byte offset {}", offset)?;
+        }
+
+        if let Some(hint) = self.hint() {
+            write!(f, " ({})", hint)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.kind {
+            ErrorKind::Io(err) => Some(err),
+            ErrorKind::Encode(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+        Error::new(kind)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::new(ErrorKind::Io(err))
+    }
+}
+
+impl<W> From<io::IntoInnerError<W>> for Error {
+    fn from(err: io::IntoInnerError<W>) -> Self {
+        Error::new(ErrorKind::Io(err.into_error()))
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(err: bincode::Error) -> Self {
+        Error::new(ErrorKind::Encode(err))
+    }
+}
+
+/// attach context to any result convertible to [Error]
+pub trait ResultExt<T> {
+    fn phase(self, phase: Phase) -> Result<T>;
+
+    fn file<P: AsRef<Path>>(self, file: P) -> Result<T>;
+
+    fn offset(self, offset: u64) -> Result<T>;
+}
+
+impl<T, E: Into<Error>> ResultExt<T> for std::result::Result<T, E> {
+    fn phase(self, phase: Phase) -> Result<T> {
+        self.map_err(|err| err.into().with_phase(phase))
+    }
+
+    fn file<P: AsRef<Path>>(self, file: P) -> Result<T> {
+        self.map_err(|err| err.into().with_file(file))
+    }
+
+    fn offset(self, offset: u64) -> Result<T> {
+        self.map_err(|err| err.into().with_offset(offset))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Error, ErrorKind, Phase, ResultExt};
+    use std::io;
+
+    #[test]
+    fn test_context() {
+        let res: Result<(), io::Error> = Err(io::Error::from_raw_os_error(libc::ENOSPC));
+        let err = res.offset(42).file("input.txt").phase(Phase::Split).unwrap_err();
+
+        assert_eq!(err.phase, Some(Phase::Split));
+        assert_eq!(err.offset, Some(42));
+
+        let msg = err.to_string();
+        assert!(msg.starts_with("split failed: "), "{}", msg);
+        assert!(msg.contains("in input.txt at byte offset 42"), "{}", msg);
+        assert!(msg.contains("TMPDIR") && msg.contains("--state-dir"), "{}", msg);
+    }
+
+    #[test]
+    fn test_fd_hint() {
+        let emfile = || Error::from(io::Error::from_raw_os_error(libc::EMFILE));
+
+        assert!(emfile().with_phase(Phase::Merge).to_string().contains("--max-fan-in"));
+
+        let msg = emfile().with_phase(Phase::Split).to_string();
+        assert!(msg.contains("--memory") && !msg.contains("--max-fan-in"), "{}", msg);
+    }
+
+    #[test]
+    fn test_inner_context_kept() {
+        let err = Error::new(ErrorKind::InvalidUtf8)
+            .with_phase(Phase::Read)
+            .with_offset(7)
+            .with_phase(Phase::Split)
+            .with_offset(0);
+
+        assert_eq!(err.phase, Some(Phase::Read));
+        assert_eq!(err.offset, Some(7));
+    }
+}
@@ -0,0 +1,246 @@
+use std::path::Path;
+
+use serde_json::Value;
+
+use crate::error::{Error, ErrorKind, Phase};
+use crate::token::Tokenizer;
+use crate::v1::io::{ChunkError, ChunkFile, ReadOptions};
+
+/// fields of a delimited `line`, quotes removed
+///
+/// a field starting with `"` is quoted, it may hold separators and `""` for
+/// a quote. text after its closing quote is kept as is, an unclosed quote
+/// runs to the end of the line.
+fn split_fields(line: &[u8], separator: u8) -> Vec<Vec<u8>> {
+    let mut fields = Vec::new();
+    let mut pos = 0;
+
+    loop {
+        let mut field = Vec::new();
+
+        if line.get(pos) == Some(&b'"') {
+            pos += 1;
+
+            while pos < line.len() {
+                match (line[pos], line.get(pos + 1)) {
+                    (b'"', Some(b'"')) => {
+                        field.push(b'"');
+                        pos += 2;
+                    }
+                    (b'"', _) => {
+                        pos += 1;
+                        break;
+                    }
+                    (byte, _) => {
+                        field.push(byte);
+                        pos += 1;
+                    }
+                }
+            }
+        }
+
+        let end = line[pos..].iter().position(|it| *it == separator).map_or(line.len(), |it| pos + it);
+        field.extend_from_slice(&line[pos..end]);
+        fields.push(field);
+
+        if end == line.len() {
+            return fields;
+        }
+
+        pos = end + 1;
+    }
+}
+
+/// CsvTokenizer takes one column of CSV or TSV records as the key.
+///
+/// records missing the column have no key. a record is a line, quoted
+/// fields can't span lines.
+#[derive(Debug)]
+pub struct CsvTokenizer {
+    separator: u8,
+    /// 0-based
+    column: usize,
+    /// records equal to the header line are skipped
+    header: Option<Vec<u8>>,
+}
+
+impl CsvTokenizer {
+    /// `field` is a 1-based column number, or a column name looked up in
+    /// the first record of `path`
+    pub fn open<P: AsRef<Path>>(path: P, options: &ReadOptions, separator: u8, field: &str) -> Result<Self, Error> {
+        if let Ok(column) = field.parse::<usize>() {
+            if column > 0 {
+                return Ok(CsvTokenizer {
+                    separator,
+                    column: column - 1,
+                    header: None,
+                });
+            }
+        }
+
+        let mut header = read_header(path, options)?;
+        options.normalizer.line(&mut header);
+
+        let column = split_fields(&header, separator)
+            .iter()
+            .position(|it| it == field.as_bytes())
+            .ok_or_else(|| invalid_field(format!("no column {} in the header", field)))?;
+
+        Ok(CsvTokenizer {
+            separator,
+            column,
+            header: Some(header),
+        })
+    }
+}
+
+impl Tokenizer for CsvTokenizer {
+    fn tokenize(&self, line: Vec<u8>, tokens: &mut Vec<(usize, Vec<u8>)>) {
+        if self.header.as_ref() == Some(&line) {
+            return;
+        }
+
+        if let Some(field) = split_fields(&line, self.separator).into_iter().nth(self.column) {
+            tokens.push((0, field));
+        }
+    }
+
+    fn keeps_start(&self) -> bool {
+        true
+    }
+}
+
+/// JsonTokenizer takes the value at a dotted path of JSON Lines records as the key.
+///
+/// strings are taken without quotes, other values as compact JSON. records
+/// that aren't JSON, or have null or nothing at the path, have no key.
+#[derive(Debug)]
+pub struct JsonTokenizer {
+    /// object keys, or array indexes
+    path: Vec<String>,
+}
+
+impl JsonTokenizer {
+    /// `path` like `user.id` or `items.0.name`
+    pub fn new(path: &str) -> Result<Self, Error> {
+        let path: Vec<String> = path.split('.').map(str::to_owned).collect();
+
+        if path.iter().any(String::is_empty) {
+            return Err(invalid_field(format!("empty key in path {}", path.join("."))));
+        }
+
+        Ok(JsonTokenizer { path })
+    }
+
+    fn lookup<'a>(&self, mut value: &'a Value) -> Option<&'a Value> {
+        for key in &self.path {
+            value = match value {
+                Value::Object(map) => map.get(key)?,
+                Value::Array(items) => items.get(key.parse::<usize>().ok()?)?,
+                _ => return None,
+            };
+        }
+
+        Some(value)
+    }
+}
+
+impl Tokenizer for JsonTokenizer {
+    fn tokenize(&self, line: Vec<u8>, tokens: &mut Vec<(usize, Vec<u8>)>) {
+        let record = match serde_json::from_slice::<Value>(&line) {
+            Ok(record) => record,
+            Err(_) => return,
+        };
+
+        match self.lookup(&record) {
+            None | Some(Value::Null) => {}
+            Some(Value::String(text)) => tokens.push((0, text.clone().into_bytes())),
+            Some(value) => tokens.push((0, value.to_string().into_bytes())),
+        }
+    }
+
+    fn keeps_start(&self) -> bool {
+        true
+    }
+}
+
+fn invalid_field(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidArgument(format!("--field: {}", msg))).with_phase(Phase::Plan)
+}
+
+/// first record of `path`
+fn read_header<P: AsRef<Path>>(path: P, options: &ReadOptions) -> Result<Vec<u8>, Error> {
+    let mut io = ChunkFile::open(path, options)?;
+
+    loop {
+        match io.next_word() {
+            Ok((header, _)) => return Ok(header),
+            Err(ChunkError::NextChunk) => {
+                io.load_chunk()?;
+            }
+            Err(ChunkError::Eof) => return Ok(Vec::new()),
+            Err(ChunkError::Cancelled(_)) => return Err(ErrorKind::Cancelled.into()),
+            Err(ChunkError::Fatal(err)) => return Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{split_fields, CsvTokenizer, JsonTokenizer};
+    use crate::token::Tokenizer;
+    use crate::v1::io::ReadOptions;
+    use std::io::Write;
+
+    fn keys(tokenizer: &dyn Tokenizer, line: &str) -> Vec<(usize, String)> {
+        let mut tokens = Vec::new();
+        tokenizer.tokenize(line.as_bytes().to_vec(), &mut tokens);
+
+        tokens
+            .into_iter()
+            .map(|(start, token)| (start, String::from_utf8(token).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_csv() {
+        assert_eq!(
+            split_fields(b"a,\"b,\"\"c\"\"\",,\"d\"e", b','),
+            vec![b"a".to_vec(), b"b,\"c\"".to_vec(), b"".to_vec(), b"de".to_vec()]
+        );
+        assert_eq!(split_fields(b"\"a,b", b','), vec![b"a,b".to_vec()]);
+
+        let mut tmp = tempfile::NamedTempFile::new().unwrap();
+        writeln!(tmp, "id\tuser").unwrap();
+        writeln!(tmp, "1\tbob").unwrap();
+
+        let options = ReadOptions::default();
+
+        let by_name = CsvTokenizer::open(tmp.path(), &options, b'\t', "user").unwrap();
+        assert_eq!(keys(&by_name, "id\tuser"), vec![]);
+        assert_eq!(keys(&by_name, "1\tbob"), vec![(0, "bob".into())]);
+        assert_eq!(keys(&by_name, "2"), vec![]);
+
+        let by_number = CsvTokenizer::open(tmp.path(), &options, b',', "2").unwrap();
+        assert_eq!(keys(&by_number, "2,\"x, y\",z"), vec![(0, "x, y".into())]);
+
+        assert!(CsvTokenizer::open(tmp.path(), &options, b'\t', "name").is_err());
+    }
+
+    #[test]
+    fn test_json() {
+        let tokenizer = JsonTokenizer::new("user.id").unwrap();
+
+        assert_eq!(keys(&tokenizer, r#"{"user": {"id": "u1"}}"#), vec![(0, "u1".into())]);
+        assert_eq!(keys(&tokenizer, r#"{"user": {"id": 42}}"#), vec![(0, "42".into())]);
+        assert_eq!(keys(&tokenizer, r#"{"user": {"id": [1, 2]}}"#), vec![(0, "[1,2]".into())]);
+        assert_eq!(keys(&tokenizer, r#"{"user": {"id": null}}"#), vec![]);
+        assert_eq!(keys(&tokenizer, r#"{"user": 1}"#), vec![]);
+        assert_eq!(keys(&tokenizer, "not json"), vec![]);
+
+        let index = JsonTokenizer::new("items.1").unwrap();
+        assert_eq!(keys(&index, r#"{"items": ["a", "b"]}"#), vec![(0, "b".into())]);
+
+        assert!(JsonTokenizer::new("user..id").is_err());
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+
+use regex::bytes::Regex;
+
+use crate::error::{Error, Phase, ResultExt};
+use crate::key::Fingerprint;
+use crate::normalize::Normalizer;
+
+/// Stopwords are keys that are never counted.
+#[derive(Default)]
+pub struct Stopwords(HashSet<Vec<u8>>);
+
+impl Stopwords {
+    pub fn new<I: IntoIterator<Item = Vec<u8>>>(words: I) -> Self {
+        Stopwords(words.into_iter().collect())
+    }
+
+    /// load stopword files, words separated by whitespace, `#` starts a comment line
+    ///
+    /// words go through `normalizer` like tokens, so they're compared as keys.
+    pub fn open<P: AsRef<Path>>(paths: &[P], normalizer: &Normalizer) -> Result<Self, Error> {
+        let mut words = HashSet::new();
+
+        for path in paths {
+            let path = path.as_ref();
+            let reader = BufReader::new(File::open(path).file(path).phase(Phase::Plan)?);
+
+            for line in reader.lines() {
+                let line = line.file(path).phase(Phase::Plan)?;
+
+                if line.trim_start().starts_with('#') {
+                    continue;
+                }
+
+                for word in line.split_whitespace() {
+                    if let Some((_, key)) = normalizer.token(word.as_bytes().to_vec()) {
+                        words.insert(key);
+                    }
+                }
+            }
+        }
+
+        Ok(Stopwords::new(words))
+    }
+}
+
+impl fmt::Debug for Stopwords {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Stopwords({}, {})", self.0.len(), Fingerprint::of_set(&self.0))
+    }
+}
+
+/// Filter drops keys before they're counted.
+///
+/// it runs after the normalizer, dropped keys never reach a spill file.
+/// lengths are in chars, invalid UTF-8 bytes count one each.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    pub stopwords: Option<Arc<Stopwords>>,
+    pub min_len: usize,
+    pub max_len: Option<usize>,
+    /// keys have to match it
+    pub include: Option<Regex>,
+    /// keys must not match it
+    pub exclude: Option<Regex>,
+}
+
+impl Filter {
+    /// `key` is counted
+    pub fn keep(&self, key: &[u8]) -> bool {
+        if self.min_len > 0 || self.max_len.is_some() {
+            let len = char_len(key);
+
+            if len < self.min_len || self.max_len.is_some_and(|max| len > max) {
+                return false;
+            }
+        }
+
+        self.keep_content(key)
+    }
+
+    /// `key` of a too long line is counted
+    ///
+    /// the line is longer than any `max_len` makes sense for, and than any
+    /// `min_len`. stopwords and regexes see the key, its head and suffix.
+    pub fn keep_long(&self, key: &[u8]) -> bool {
+        self.max_len.is_none() && self.keep_content(key)
+    }
+
+    fn keep_content(&self, key: &[u8]) -> bool {
+        if self.stopwords.as_ref().is_some_and(|it| it.0.contains(key)) {
+            return false;
+        }
+
+        if self.include.as_ref().is_some_and(|it| !it.is_match(key)) {
+            return false;
+        }
+
+        !self.exclude.as_ref().is_some_and(|it| it.is_match(key))
+    }
+}
+
+fn char_len(key: &[u8]) -> usize {
+    key.utf8_chunks()
+        .map(|chunk| chunk.valid().chars().count() + chunk.invalid().len())
+        .sum()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Filter, Stopwords};
+    use crate::normalize::{Case, Normalizer};
+    use regex::bytes::Regex;
+    use std::io::Write;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_filter() {
+        let mut tmp = tempfile::NamedTempFile::new().unwrap();
+        writeln!(tmp, "# English").unwrap();
+        writeln!(tmp, "The a  an").unwrap();
+
+        let normalizer = Normalizer {
+            case: Case::Ascii,
+            ..Normalizer::default()
+        };
+
+        let filter = Filter {
+            stopwords: Some(Arc::new(Stopwords::open(&[tmp.path()], &normalizer).unwrap())),
+            min_len: 2,
+            max_len: Some(5),
+            include: None,
+            exclude: Some(Regex::new("^[0-9]+$").unwrap()),
+        };
+
+        assert!(filter.keep(b"word"));
+        assert!(filter.keep("\u{e9}t\u{e9}".as_bytes()));
+        assert!(!filter.keep(b"the"));
+        assert!(!filter.keep(b"x"));
+        assert!(!filter.keep(b"longer"));
+        assert!(!filter.keep(b"2024"));
+        assert!(!filter.keep_long("word\u{2026}[100 bytes, 0]".as_bytes()));
+
+        let include = Filter {
+            include: Some(Regex::new("^err").unwrap()),
+            ..Filter::default()
+        };
+
+        assert!(include.keep(b"error"));
+        assert!(!include.keep(b"warn"));
+        assert!(include.keep_long("error\u{2026}[100 bytes, 0]".as_bytes()));
+        assert!(!include.keep_long("warn\u{2026}[100 bytes, 0]".as_bytes()));
+
+        // same size, other words
+        let edited = Stopwords::new(vec![b"an".to_vec()]);
+        assert_ne!(format!("{:?}", edited), format!("{:?}", Stopwords::new(vec![b"a".to_vec()])));
+    }
+}
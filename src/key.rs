@@ -0,0 +1,309 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::Path;
+
+use crate::error::{Error, ErrorKind, Phase, ResultExt};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::token::TokenReader;
+use crate::v1::io::{ChunkError, Location, ReadOptions};
+use crate::v2::utils::{hash_with_seed, SipHasher};
+
+/// read buffer for reading a single word back
+const RECOVER_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// key a word is counted by in spill files
+///
+/// words come in as bytes already checked by the reader's [Encoding]
+pub trait WordKey: Ord + Hash + Clone + Serialize + DeserializeOwned {
+    fn from_word(word: Vec<u8>) -> Self;
+
+    /// bytes partitions are hashed by, they must not change between builds
+    fn key_bytes(&self) -> Cow<'_, [u8]>;
+}
+
+impl WordKey for String {
+    fn from_word(word: Vec<u8>) -> Self {
+        match String::from_utf8(word) {
+            Ok(word) => word,
+            // only in bytes mode, which counts by `Vec<u8>` instead
+            Err(err) => String::from_utf8_lossy(err.as_bytes()).into_owned(),
+        }
+    }
+
+    fn key_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.as_bytes())
+    }
+}
+
+impl WordKey for Vec<u8> {
+    fn from_word(word: Vec<u8>) -> Self {
+        word
+    }
+
+    fn key_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self)
+    }
+}
+
+/// printable form of a byte word, invalid UTF-8 bytes as `\xNN`
+pub fn escape_bytes(word: &[u8]) -> String {
+    let mut out = String::with_capacity(word.len());
+
+    for chunk in word.utf8_chunks() {
+        out.push_str(chunk.valid());
+
+        for byte in chunk.invalid() {
+            out.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+
+    out
+}
+
+/// 128-bit fingerprint of a word, spill size doesn't depend on word length
+#[derive(Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, Serialize, Deserialize, Debug)]
+pub struct Fingerprint(pub u64, pub u64);
+
+impl Fingerprint {
+    pub fn of(word: &[u8]) -> Self {
+        Fingerprint(hash_with_seed(word, 0x5eed_0001), hash_with_seed(word, 0x5eed_0002))
+    }
+
+    /// fingerprint of distinct `entries` in any order, the sum of theirs
+    pub fn of_set<B: AsRef<[u8]>, I: IntoIterator<Item = B>>(entries: I) -> Self {
+        entries.into_iter().fold(Fingerprint(0, 0), |sum, it| {
+            let it = Fingerprint::of(it.as_ref());
+
+            Fingerprint(sum.0.wrapping_add(it.0), sum.1.wrapping_add(it.1))
+        })
+    }
+
+    /// bytes of a map entry for [Fingerprint::of_set], `key` is length prefixed
+    /// so that moving bytes between key and value changes them
+    pub fn entry_bytes(key: &[u8], value: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(8 + key.len() + value.len());
+
+        bytes.extend_from_slice(&(key.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(key);
+        bytes.extend_from_slice(value);
+
+        bytes
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}{:016x}", self.0, self.1)
+    }
+}
+
+/// fingerprint of a word fed piece by piece, for words too long to keep
+///
+/// it's not the same value as [Fingerprint::of] of the whole word.
+pub struct FingerprintHasher(SipHasher, SipHasher);
+
+impl FingerprintHasher {
+    pub fn new() -> Self {
+        FingerprintHasher(SipHasher::new_with_keys(0x5eed_0001, 0), SipHasher::new_with_keys(0x5eed_0002, 0))
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        self.0.write(bytes);
+        self.1.write(bytes);
+    }
+
+    /// fingerprint of everything written, `len` bytes in total
+    pub fn finish(&self, len: u64) -> Fingerprint {
+        let mut lhs = self.0.clone();
+        let mut rhs = self.1.clone();
+
+        lhs.write_u64(len);
+        rhs.write_u64(len);
+
+        Fingerprint(lhs.finish(), rhs.finish())
+    }
+}
+
+impl Default for FingerprintHasher {
+    fn default() -> Self {
+        FingerprintHasher::new()
+    }
+}
+
+impl WordKey for Fingerprint {
+    fn from_word(word: Vec<u8>) -> Self {
+        Fingerprint::of(&word)
+    }
+
+    fn key_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(16);
+
+        bytes.extend_from_slice(&self.0.to_le_bytes());
+        bytes.extend_from_slice(&self.1.to_le_bytes());
+
+        Cow::Owned(bytes)
+    }
+}
+
+/// read the word at `location` of the source back, and check it against the
+/// fingerprint it was counted by, `options` must be the ones it was read with
+///
+/// only the read back is verified: two words with the same fingerprint were
+/// counted as one, and that isn't detected here.
+pub fn recover_word<P: AsRef<Path>>(
+    path: P,
+    location: Location,
+    fingerprint: Fingerprint,
+    options: &ReadOptions,
+) -> Result<Vec<u8>, Error> {
+    let path = path.as_ref();
+    let offset = location.offset;
+
+    let word = read_word(path, location, options, false).offset(offset).phase(Phase::Recover)?;
+
+    if Fingerprint::of(&word) != fingerprint {
+        let err = Error::new(ErrorKind::FingerprintMismatch);
+
+        return Err(err.with_phase(Phase::Recover).with_file(path).with_offset(offset));
+    }
+
+    Ok(word)
+}
+
+/// the word at `location` as written in the source, only trimmed like its key
+pub fn read_surface<P: AsRef<Path>>(path: P, location: Location, options: &ReadOptions) -> Result<Vec<u8>, Error> {
+    let path = path.as_ref();
+
+    read_word(path, location, options, true)
+        .file(path)
+        .offset(location.offset)
+        .phase(Phase::Recover)
+}
+
+/// same reader as the scan, so a long word comes back as the same key,
+/// or with `surface` as it's written
+fn read_word(path: &Path, location: Location, options: &ReadOptions, surface: bool) -> Result<Vec<u8>, Error> {
+    let options = ReadOptions {
+        chunk_size: RECOVER_CHUNK_SIZE,
+        ..options.clone()
+    };
+
+    let mut io = TokenReader::open(path, &options)?;
+    io.seek(location)?;
+
+    if surface {
+        io = io.with_surface();
+    }
+
+    loop {
+        match io.next_token() {
+            Ok((word, _)) => return Ok(word),
+            Err(ChunkError::NextChunk) => {
+                io.load_chunk()?;
+            }
+            Err(ChunkError::Eof) => return Err(ErrorKind::Io(io::ErrorKind::UnexpectedEof.into()).into()),
+            Err(ChunkError::Cancelled(_)) => return Err(ErrorKind::Cancelled.into()),
+            Err(ChunkError::Fatal(err)) => return Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{escape_bytes, read_surface, recover_word, Fingerprint, FingerprintHasher, WordKey};
+    use crate::filter::{Filter, Stopwords};
+    use crate::normalize::{Case, Normalizer};
+    use crate::token::{Ngram, TokenizerKind};
+    use crate::v1::io::{Encoding, Location, ReadOptions};
+    use std::io::Write;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_recover() {
+        let mut tmp = tempfile::NamedTempFile::new().unwrap();
+        tmp.write_all(b"qwer\nabcd\nzx\xffcv").unwrap();
+
+        let strict = ReadOptions::default();
+        let bytes = ReadOptions {
+            encoding: Encoding::Bytes,
+            ..ReadOptions::default()
+        };
+
+        let word = recover_word(tmp.path(), Location::new(5, 2), Fingerprint::of(b"abcd"), &strict).unwrap();
+        assert_eq!(word, b"abcd");
+
+        let word = recover_word(tmp.path(), Location::new(10, 3), Fingerprint::of(b"zx\xffcv"), &bytes).unwrap();
+        assert_eq!(word, b"zx\xffcv");
+
+        assert!(recover_word(tmp.path(), Location::new(10, 3), Fingerprint::of(b"zx\xffcv"), &strict).is_err());
+        assert!(recover_word(tmp.path(), Location::new(0, 1), Fingerprint::of(b"abcd"), &strict).is_err());
+    }
+
+    #[test]
+    fn test_surface() {
+        let mut tmp = tempfile::NamedTempFile::new().unwrap();
+        tmp.write_all(b"qwer (Abcd), zx\r\n").unwrap();
+
+        let options = ReadOptions {
+            tokenizer: TokenizerKind::Whitespace.build(),
+            normalizer: Normalizer {
+                case: Case::Unicode,
+                trim_punctuation: true,
+                ..Normalizer::default()
+            },
+            ..ReadOptions::default()
+        };
+
+        // key is folded, surface is only trimmed
+        let word = recover_word(tmp.path(), Location::new(6, 1), Fingerprint::of(b"abcd"), &options).unwrap();
+        assert_eq!(word, b"abcd");
+        assert_eq!(read_surface(tmp.path(), Location::new(6, 1), &options).unwrap(), b"Abcd");
+
+        // tokens dropped by their keys are dropped from the surface too
+        let bigram = ReadOptions {
+            filter: Filter {
+                stopwords: Some(Arc::new(Stopwords::new(vec![b"abcd".to_vec()]))),
+                ..Filter::default()
+            },
+            ngram: Ngram {
+                size: 2,
+                across_lines: false,
+            },
+            ..options
+        };
+        assert_eq!(read_surface(tmp.path(), Location::new(0, 1), &bigram).unwrap(), b"qwer zx");
+    }
+
+    #[test]
+    fn test_hasher() {
+        let mut lhs = FingerprintHasher::new();
+        lhs.write(b"qw");
+        lhs.write(b"er");
+
+        let mut rhs = FingerprintHasher::new();
+        rhs.write(b"qwer");
+
+        assert_eq!(lhs.finish(4), rhs.finish(4));
+        assert_ne!(lhs.finish(4), lhs.finish(5));
+    }
+
+    #[test]
+    fn test_key_bytes() {
+        assert_eq!(&*"qwer".to_string().key_bytes(), b"qwer");
+        assert_eq!(&*b"qwer".to_vec().key_bytes(), b"qwer");
+        assert_eq!(&*Fingerprint(1, 2).key_bytes(), &[1, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0]);
+
+        // bytes moved from key to value make another entry
+        assert_ne!(Fingerprint::entry_bytes(b"ab", b"c"), Fingerprint::entry_bytes(b"a", b"bc"));
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape_bytes(b"qwer"), "qwer");
+        assert_eq!(escape_bytes(b"zx\xffcv"), "zx\\xffcv");
+    }
+}
@@ -1,41 +1,623 @@
-use std::fs::File;
-use std::io::BufReader;
-use std::path::Path;
+use std::process;
+use std::str::FromStr;
+use std::sync::Arc;
 
-use bincode::ErrorKind;
-use clap::{App, Arg};
-use failure::Error;
+use clap::{App, Arg, ArgMatches};
+use regex::bytes::Regex;
 
+mod approx;
+mod cancel;
+mod error;
+mod field;
+mod filter;
+mod key;
+mod normalize;
+mod preflight;
+mod segment;
+mod spill;
+mod stem;
+mod token;
 mod v1;
 mod v2;
 
+use crate::approx::{DEFAULT_CANDIDATES, DEFAULT_SKETCH_DEPTH, DEFAULT_SKETCH_WIDTH};
+use crate::error::{Error, ErrorKind, Phase, ResultExt};
+use crate::field::{CsvTokenizer, JsonTokenizer};
+use crate::filter::{Filter, Stopwords};
+use crate::key::{escape_bytes, read_surface, recover_word, Fingerprint, WordKey};
+use crate::normalize::{Aliases, Case, Form, Normalizer};
+use crate::preflight::TempEstimate;
+use crate::segment::DictTokenizer;
+use crate::token::{Ngram, RegexTokenizer, Tokenizer, TokenizerKind};
+use crate::v1::io::{Delimiter, Encoding, Location, ReadOptions, DEFAULT_MAX_WORD};
+use crate::v1::plan::{MergePlanner, DEFAULT_MAX_FAN_IN, DEFAULT_MERGE_MEMORY};
+use crate::v2::checkpoint::StateDir;
+use crate::v2::fanout::DEFAULT_MEMORY;
+use crate::v2::utils::HASH_VERSION;
 
 fn main() {
     let app = App::new("first-non-repeating word")
         .version("0.1.0")
         .author("tyan boot")
-        .arg(Arg::with_name("file").help("input file").required(true).takes_value(true));
+        .arg(Arg::with_name("file").help("input file").required(true).takes_value(true))
+        .arg(
+            Arg::with_name("strategy")
+                .long("strategy")
+                .help("v1: sort and merge, v2: hash partition, approx: sketch without temp files")
+                .possible_values(&["v1", "v2", "approx"])
+                .default_value("v2"),
+        )
+        .arg(
+            Arg::with_name("merge-memory")
+                .long("merge-memory")
+                .help("v1: memory for run readers during merge, in MiB")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-fan-in")
+                .long("max-fan-in")
+                .help("v1: max runs opened at the same time during merge")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("memory")
+                .long("memory")
+                .help("v2: memory budget used to choose partition count, in MiB")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("stats")
+                .long("stats")
+                .help("v2: print partition statistics to stderr"),
+        )
+        .arg(
+            Arg::with_name("state-dir")
+                .long("state-dir")
+                .help("v2: keep named temp files and checkpoints in this directory")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("checkpoint-interval")
+                .long("checkpoint-interval")
+                .help("v2: source bytes read between two checkpoints, in MiB")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("resume")
+                .long("resume")
+                .requires("state-dir")
+                .help("v2: continue from the last checkpoint in --state-dir"),
+        )
+        .arg(
+            Arg::with_name("sketch-width")
+                .long("sketch-width")
+                .help("approx: counters per sketch row, one byte each")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sketch-depth")
+                .long("sketch-depth")
+                .help("approx: sketch rows")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("candidates")
+                .long("candidates")
+                .help("approx: max early singletons kept")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("encoding")
+                .long("encoding")
+                .help("strict: fail on invalid UTF-8, lossy: replace it, bytes: count raw byte strings")
+                .possible_values(&["strict", "lossy", "bytes"])
+                .default_value("strict"),
+        )
+        .arg(
+            Arg::with_name("delimiter")
+                .long("delimiter")
+                .help("bytes that end a record instead of `\\n`, with escapes `\\n` `\\r` `\\t` `\\0` `\\\\` `\\xNN`")
+                .takes_value(true)
+                .conflicts_with("zero-terminated"),
+        )
+        .arg(
+            Arg::with_name("zero-terminated")
+                .short("z")
+                .long("zero-terminated")
+                .help("records end with NUL, like `sort -z`"),
+        )
+        .arg(
+            Arg::with_name("tokenizer")
+                .long("tokenizer")
+                .help(
+                    "line: whole line is a word, whitespace: split on ASCII spaces, unicode: UAX #29 words, \
+                     dict: segment CJK text by --dictionary, csv/tsv/jsonl: --field of each record is the word, \
+                     regex: matches of --pattern are the words",
+                )
+                .possible_values(&["line", "whitespace", "unicode", "dict", "csv", "tsv", "jsonl", "regex"])
+                .default_value("line")
+                .requires_ifs(&[
+                    ("dict", "dictionary"),
+                    ("csv", "field"),
+                    ("tsv", "field"),
+                    ("jsonl", "field"),
+                    ("regex", "pattern"),
+                ]),
+        )
+        .arg(
+            Arg::with_name("pattern")
+                .long("pattern")
+                .help("regex: pattern of the words, like `\\d+\\.\\d+\\.\\d+\\.\\d+`")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("group")
+                .long("group")
+                .help("regex: capture group number or name taken as the word, 0 is the whole match")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::with_name("field")
+                .long("field")
+                .help("csv/tsv: 1-based column number or header name, jsonl: dotted path like user.id")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dictionary")
+                .long("dictionary")
+                .help("dict: file of `word [frequency]` lines")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ngram")
+                .long("ngram")
+                .help("count every N tokens in a row as one key, joined by a space")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ngram-across-lines")
+                .long("ngram-across-lines")
+                .help("n-grams may take tokens of the following lines")
+                .requires("ngram"),
+        )
+        .arg(
+            Arg::with_name("strip-cr")
+                .long("strip-cr")
+                .help("drop \\r at the end of CRLF lines"),
+        )
+        .arg(
+            Arg::with_name("case-fold")
+                .long("case-fold")
+                .help("lowercase words, ascii: only A-Z")
+                .possible_values(&["keep", "ascii", "unicode"])
+                .default_value("keep"),
+        )
+        .arg(
+            Arg::with_name("unicode-form")
+                .long("unicode-form")
+                .help("Unicode normalization of words")
+                .possible_values(&["keep", "nfc", "nfkc"])
+                .default_value("keep"),
+        )
+        .arg(
+            Arg::with_name("trim-punctuation")
+                .long("trim-punctuation")
+                .help("drop leading and trailing characters that are neither letters nor digits"),
+        )
+        .arg(
+            Arg::with_name("aliases")
+                .long("aliases")
+                .help("file of `canonical alias...` lines, aliases are counted as their canonical word")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("stem")
+                .long("stem")
+                .help("count English words by their Porter stem"),
+        )
+        .arg(
+            Arg::with_name("stopwords")
+                .long("stopwords")
+                .help("file of words that aren't counted, can be given more than once")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("min-length")
+                .long("min-length")
+                .help("skip keys shorter than this many chars")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-length")
+                .long("max-length")
+                .help("skip keys longer than this many chars")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("include")
+                .long("include")
+                .help("only count keys matching this regex")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("exclude")
+                .long("exclude")
+                .help("skip keys matching this regex")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-word")
+                .long("max-word")
+                .help("longer words are counted by length and fingerprint, in KiB")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("fingerprint")
+                .long("fingerprint")
+                .help("spill 128-bit fingerprints instead of words, the answer is read back from source"),
+        )
+        .arg(
+            Arg::with_name("max-temp-bytes")
+                .long("max-temp-bytes")
+                .help("most bytes of temp files at the same time, the run fails once over")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("skip-preflight")
+                .long("skip-preflight")
+                .help("start without comparing estimated temp size to free space"),
+        );
 
     let matches = app.get_matches();
 
+    if let Err(err) = cancel::install_handler() {
+        eprintln!("warning: can't handle SIGINT and SIGTERM, {}", err);
+    }
+
+    if let Err(err) = run(&matches) {
+        eprintln!("error: {}", err);
+
+        if err.is_cancelled() {
+            if matches.is_present("state-dir") {
+                eprintln!("progress is saved in --state-dir, continue with --resume");
+            }
+
+            // as if killed by SIGINT
+            process::exit(130);
+        }
+
+        process::exit(1);
+    }
+}
+
+/// parse an optional argument
+fn parse_arg<T: FromStr>(matches: &ArgMatches, name: &str, default: T) -> Result<T, Error> {
+    match matches.value_of(name) {
+        Some(value) => value.parse::<T>().map_err(|_| {
+            Error::new(ErrorKind::InvalidArgument(format!("--{} {}", name, value))).with_phase(Phase::Plan)
+        }),
+        None => Ok(default),
+    }
+}
+
+fn run(matches: &ArgMatches) -> Result<(), Error> {
     let input = matches.value_of("file").unwrap();
 
+    let merge_memory = parse_arg(matches, "merge-memory", DEFAULT_MERGE_MEMORY >> 20)? << 20;
+    let max_fan_in = parse_arg(matches, "max-fan-in", DEFAULT_MAX_FAN_IN)?;
+    let memory = parse_arg(matches, "memory", DEFAULT_MEMORY >> 20)? << 20;
+
+    let normalizer = Normalizer {
+        strip_cr: matches.is_present("strip-cr"),
+        case: parse_arg(matches, "case-fold", Case::Keep)?,
+        form: parse_arg(matches, "unicode-form", Form::Keep)?,
+        trim_punctuation: matches.is_present("trim-punctuation"),
+        aliases: match matches.value_of("aliases") {
+            Some(path) => Some(Arc::new(Aliases::open(path)?)),
+            None => None,
+        },
+        stem: matches.is_present("stem"),
+    };
+
+    let mut options = ReadOptions {
+        encoding: parse_arg(matches, "encoding", Encoding::Strict)?,
+        max_word: parse_arg(matches, "max-word", DEFAULT_MAX_WORD >> 10)? << 10,
+        delimiter: if matches.is_present("zero-terminated") {
+            Delimiter::new(vec![0]).unwrap()
+        } else {
+            parse_arg(matches, "delimiter", Delimiter::default())?
+        },
+        filter: filter(matches, &normalizer)?,
+        normalizer,
+        ngram: Ngram {
+            size: parse_arg(matches, "ngram", 1)?,
+            across_lines: matches.is_present("ngram-across-lines"),
+        },
+        ..ReadOptions::default()
+    };
+
+    // csv reads its header the way records are read
+    options.tokenizer = tokenizer(matches, input, &options)?;
+
+    if matches.value_of("strategy") == Some("approx") {
+        let width = parse_arg(matches, "sketch-width", DEFAULT_SKETCH_WIDTH)?;
+        let depth = parse_arg(matches, "sketch-depth", DEFAULT_SKETCH_DEPTH)?;
+        let candidates = parse_arg(matches, "candidates", DEFAULT_CANDIDATES)?;
+
+        let estimate = approx::solve(input, &options, width, depth, candidates)?;
+
+        eprintln!("{}", estimate);
+
+        return print_answer(input, &estimate.answer, &options);
+    }
+
+    let quota = parse_arg(matches, "max-temp-bytes", u64::MAX)?;
+    spill::set_quota(quota);
+
+    let mut fingerprint = matches.is_present("fingerprint");
+
+    // a resumed run has its temp files already, and spills what it started with
+    if matches.is_present("resume") {
+        if let Some(dir) = matches.value_of("state-dir") {
+            let input_size = std::fs::metadata(input).file(input).phase(Phase::Read)?.len();
+            let saved = StateDir::saved_settings(dir)?;
+
+            fingerprint |= saved == Some(checkpoint_settings::<Fingerprint>(input, input_size, &options));
+        }
+    } else if !matches.is_present("skip-preflight") {
+        fingerprint = preflight(matches, input, &options, quota, fingerprint)?;
+    }
+
+    let ans = if fingerprint {
+        match solve::<Fingerprint>(matches, input, &options, merge_memory, max_fan_in, memory)? {
+            // only fingerprint is spilled, read the word back from source
+            Some((fingerprint, location)) => {
+                let word = recover_word(input, location, fingerprint, &options)?;
+
+                Some((escape_bytes(&word), location))
+            }
+            None => None,
+        }
+    } else if options.encoding == Encoding::Bytes {
+        solve::<Vec<u8>>(matches, input, &options, merge_memory, max_fan_in, memory)?
+            .map(|(word, location)| (escape_bytes(&word), location))
+    } else {
+        solve::<String>(matches, input, &options, merge_memory, max_fan_in, memory)?
+    };
+
+    print_answer(input, &ans, &options)
+}
+
+/// tokenizer chosen on the command line
+fn tokenizer(matches: &ArgMatches, input: &str, options: &ReadOptions) -> Result<Arc<dyn Tokenizer>, Error> {
+    let field = matches.value_of("field").unwrap_or_default();
+
+    match matches.value_of("tokenizer") {
+        Some("dict") => Ok(Arc::new(DictTokenizer::open(matches.value_of("dictionary").unwrap())?)),
+        Some("csv") => Ok(Arc::new(CsvTokenizer::open(input, options, b',', field)?)),
+        Some("tsv") => Ok(Arc::new(CsvTokenizer::open(input, options, b'\t', field)?)),
+        Some("jsonl") => Ok(Arc::new(JsonTokenizer::new(field)?)),
+        Some("regex") => {
+            let pattern = matches.value_of("pattern").unwrap();
+
+            Ok(Arc::new(RegexTokenizer::new(pattern, matches.value_of("group").unwrap())?))
+        }
+        _ => Ok(parse_arg(matches, "tokenizer", TokenizerKind::Line)?.build()),
+    }
+}
+
+fn filter(matches: &ArgMatches, normalizer: &Normalizer) -> Result<Filter, Error> {
+    let regex = |name: &str| -> Result<Option<Regex>, Error> {
+        match matches.value_of(name) {
+            Some(value) => Regex::new(value).map(Some).map_err(|err| {
+                Error::new(ErrorKind::InvalidArgument(format!("--{} {}: {}", name, value, err))).with_phase(Phase::Plan)
+            }),
+            None => Ok(None),
+        }
+    };
+
+    Ok(Filter {
+        stopwords: match matches.values_of("stopwords") {
+            Some(paths) => Some(Arc::new(Stopwords::open(&paths.collect::<Vec<_>>(), normalizer)?)),
+            None => None,
+        },
+        min_len: parse_arg(matches, "min-length", 0)?,
+        max_len: match matches.value_of("max-length") {
+            Some(_) => Some(parse_arg(matches, "max-length", 0)?),
+            None => None,
+        },
+        include: regex("include")?,
+        exclude: regex("exclude")?,
+    })
+}
+
+/// print the answer to stdout, `"key" at line N, byte offset M` or
+/// `no unique word`. when keys are normalized, a second line
+/// `written as "surface"` shows how it's written at its location.
+fn print_answer(input: &str, ans: &Option<(String, Location)>, options: &ReadOptions) -> Result<(), Error> {
+    let (word, location) = match ans {
+        Some(ans) => ans,
+        None => {
+            println!("no unique word");
+            return Ok(());
+        }
+    };
+
+    println!("{:?} at {}", word, location);
+
+    if options.normalizer.changes_spelling() {
+        let surface = escape_bytes(&read_surface(input, *location, options)?);
+
+        println!("written as {:?}", surface);
+    }
+
+    Ok(())
+}
+
+/// estimate temp space of the run and compare it to free space and quota
+///
+/// returns whether to spill fingerprints, switching to them when only they fit.
+fn preflight(
+    matches: &ArgMatches,
+    input: &str,
+    options: &ReadOptions,
+    quota: u64,
+    fingerprint: bool,
+) -> Result<bool, Error> {
+    let input_size = std::fs::metadata(input).file(input).phase(Phase::Read)?.len();
+    let estimate = TempEstimate::sample(input, input_size, options)
+        .file(input)
+        .phase(Phase::Read)?;
+
+    let dir = match matches.value_of("state-dir") {
+        Some(dir) => std::path::PathBuf::from(dir),
+        None => std::env::temp_dir(),
+    };
+
+    // state directory may not be created yet
+    let free = preflight::available_at(&dir).file(&dir).phase(Phase::Plan)?;
+
+    let limit = free.min(quota);
+    let v1 = matches.value_of("strategy") == Some("v1");
+    let needed = estimate.peak(v1, fingerprint);
+
+    if matches.is_present("stats") {
+        eprint!("{}", estimate);
+        eprintln!("temp files need about {} bytes, {} available", needed, limit);
+    }
+
+    if needed <= limit {
+        return Ok(fingerprint);
+    }
+
+    if !fingerprint && estimate.peak(v1, true) <= limit {
+        eprintln!(
+            "note: temp files need about {} bytes, only {} available, spilling fingerprints instead",
+            needed, limit
+        );
+
+        return Ok(true);
+    }
+
+    Err(Error::new(ErrorKind::InsufficientTempSpace(needed, limit)).with_phase(Phase::Plan))
+}
+
+/// a checkpoint only fits the same input read the same way, into the same keys
+fn checkpoint_settings<K>(input: &str, input_size: u64, options: &ReadOptions) -> String {
+    format!(
+        "{} {} {:?} {} {:?} {:?} {:?} {:?} {:?} {} hash v{}",
+        input,
+        input_size,
+        options.encoding,
+        options.max_word,
+        options.delimiter,
+        options.tokenizer,
+        options.normalizer,
+        options.filter,
+        options.ngram,
+        std::any::type_name::<K>(),
+        HASH_VERSION
+    )
+}
+
+fn solve<K: WordKey>(
+    matches: &ArgMatches,
+    input: &str,
+    options: &ReadOptions,
+    merge_memory: u64,
+    max_fan_in: usize,
+    memory: u64,
+) -> Result<Option<(K, Location)>, Error> {
+    if matches.value_of("strategy") == Some("v1") {
+        use crate::v1::Count;
+
+        let planner = MergePlanner::new(merge_memory, max_fan_in);
+
+        let mut count = Count::new(input, options, planner)?;
+
+        count.solve().inspect_err(|err| {
+            if err.is_cancelled() {
+                eprintln!("{} sorted runs written", count.runs());
+            }
+        })
+    } else {
+        use crate::v2::checkpoint::{Stage, DEFAULT_CHECKPOINT_INTERVAL};
+        use crate::v2::count::Counter;
+        use crate::v2::fanout::FanOut;
+        use crate::v2::io::HashSplitFile;
+        use crate::v2::manifest::Manifest;
+
+        let input_size = std::fs::metadata(input).file(input).phase(Phase::Read)?.len();
+        let fanout = FanOut::detect(memory, input_size).phase(Phase::Plan)?;
+
+        let mut state = match matches.value_of("state-dir") {
+            Some(dir) => {
+                let interval = parse_arg(matches, "checkpoint-interval", DEFAULT_CHECKPOINT_INTERVAL >> 20)? << 20;
+                let settings = checkpoint_settings::<K>(input, input_size, options);
+
+                Some(StateDir::open(dir, settings, interval)?)
+            }
+            None => None,
+        };
+
+        let checkpoint = match &mut state {
+            Some(state) if matches.is_present("resume") => state.load::<K>()?,
+            Some(state) => {
+                state.reset()?;
+                None
+            }
+            None => None,
+        };
+
+        let (manifest, ans) = match checkpoint {
+            Some((Stage::Count { ans }, partitions)) => (Manifest::new(partitions), ans),
+            checkpoint => {
+                let mut spliter = HashSplitFile::<K>::new(input, options, fanout)?;
+
+                if let Some(state) = &state {
+                    spliter = spliter.with_state(state.clone());
+                }
+
+                if let Some((stage, partitions)) = checkpoint {
+                    spliter.resume(stage, partitions)?;
+                }
+
+                if let Err(err) = spliter.split() {
+                    if err.is_cancelled() {
+                        eprint!("{}", spliter.finish());
+                    }
+
+                    return Err(err);
+                }
+
+                (spliter.finish(), Vec::new())
+            }
+        };
+
+        if matches.is_present("stats") {
+            eprint!("{}", manifest);
+        }
+
+        let mut counter = Counter::new(manifest)?.with_cancel(options.cancel.clone());
 
-    use crate::v2::count::Counter;
-    use crate::v2::io::HashSplitFile;
+        if let Some(state) = state {
+            counter = counter.with_state(state, ans);
+        }
 
-    let mut spliter = HashSplitFile::new(input).unwrap();
-    spliter.split().unwrap();
-    let chunks = spliter.finish();
+        if let Err(err) = counter.run() {
+            if err.is_cancelled() {
+                if let Some(best) = counter.best_location() {
+                    eprintln!("earliest unique word so far at {}", best);
+                }
 
-    let mut counter = Counter::new(chunks).unwrap();
+                eprint!("left to count: {}", counter.remaining());
+            }
 
-    counter.run();
-    let ans = counter.finish();
+            return Err(err);
+        }
 
-    dbg!(ans);
-//    let mut count = Count::new(input).unwrap();
-//    let ans = count.solve();
-//
-//    dbg!(ans);
+        Ok(counter.finish())
+    }
 }
@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use unicode_normalization::UnicodeNormalization;
+
+use crate::error::{Error, Phase, ResultExt};
+use crate::key::Fingerprint;
+use crate::stem::stem;
+
+/// case folding of a token
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Case {
+    #[default]
+    Keep,
+    /// only `A-Z`
+    Ascii,
+    /// full Unicode lowercase
+    Unicode,
+}
+
+impl FromStr for Case {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(Case::Keep),
+            "ascii" => Ok(Case::Ascii),
+            "unicode" => Ok(Case::Unicode),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Unicode normalization form of a token
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Form {
+    #[default]
+    Keep,
+    Nfc,
+    Nfkc,
+}
+
+impl FromStr for Form {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(Form::Keep),
+            "nfc" => Ok(Form::Nfc),
+            "nfkc" => Ok(Form::Nfkc),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Aliases rewrite tokens to a canonical form.
+pub struct Aliases(HashMap<Vec<u8>, Vec<u8>>);
+
+impl Aliases {
+    /// `(alias, canonical)` pairs
+    pub fn new<I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>>(pairs: I) -> Self {
+        Aliases(pairs.into_iter().collect())
+    }
+
+    /// load an alias file, one `canonical alias...` group per line,
+    /// `#` starts a comment line
+    ///
+    /// aliases are matched after case folding and Unicode normalization, so
+    /// they're written the way keys look then.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let reader = BufReader::new(File::open(path).file(path).phase(Phase::Plan)?);
+
+        let mut pairs = Vec::new();
+
+        for line in reader.lines() {
+            let line = line.file(path).phase(Phase::Plan)?;
+            let mut words = line.split_whitespace();
+
+            let canonical = match words.next() {
+                Some(word) if !word.starts_with('#') => word,
+                _ => continue,
+            };
+
+            for alias in words {
+                pairs.push((alias.as_bytes().to_vec(), canonical.as_bytes().to_vec()));
+            }
+        }
+
+        Ok(Aliases::new(pairs))
+    }
+
+    fn rewrite(&self, token: Vec<u8>) -> Vec<u8> {
+        match self.0.get(&token) {
+            Some(canonical) => canonical.clone(),
+            None => token,
+        }
+    }
+}
+
+impl fmt::Debug for Aliases {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let entries = self.0.iter().map(|(alias, canonical)| Fingerprint::entry_bytes(alias, canonical));
+
+        write!(f, "Aliases({}, {})", self.0.len(), Fingerprint::of_set(entries))
+    }
+}
+
+/// Normalizer turns tokens into the keys they are counted by.
+///
+/// it runs between the tokenizer and the counters, the default one keeps
+/// every token as is. invalid UTF-8 of bytes mode is kept untouched.
+#[derive(Clone, Debug, Default)]
+pub struct Normalizer {
+    /// drop `\r` at the end of a line
+    pub strip_cr: bool,
+    pub case: Case,
+    pub form: Form,
+    /// drop leading and trailing characters that are neither letters nor digits
+    pub trim_punctuation: bool,
+    /// rewrite to canonical forms, before stemming
+    pub aliases: Option<Arc<Aliases>>,
+    /// Porter stemmer for English words
+    pub stem: bool,
+}
+
+impl Normalizer {
+    /// only the steps that keep a token's spelling, it reads the surface form
+    /// a key is first written as
+    pub fn surface(&self) -> Normalizer {
+        Normalizer {
+            case: Case::Keep,
+            form: Form::Keep,
+            aliases: None,
+            stem: false,
+            ..self.clone()
+        }
+    }
+
+    /// keys differ from what's written in the source
+    pub fn changes_spelling(&self) -> bool {
+        self.case != Case::Keep || self.form != Form::Keep || self.aliases.is_some() || self.stem
+    }
+
+    /// applied to a whole line before it's tokenized
+    pub fn line(&self, line: &mut Vec<u8>) {
+        if self.strip_cr && line.last() == Some(&b'\r') {
+            line.pop();
+        }
+    }
+
+    /// key of `token` with the bytes trimmed at its front,
+    /// `None` if nothing but punctuation is left
+    pub fn token(&self, token: Vec<u8>) -> Option<(usize, Vec<u8>)> {
+        let (start, mut token) = if self.trim_punctuation && !token.is_empty() {
+            let (start, end) = trim(&token);
+
+            if start == end {
+                return None;
+            }
+
+            (start, token[start..end].to_vec())
+        } else {
+            (0, token)
+        };
+
+        match self.form {
+            Form::Keep => {}
+            Form::Nfc => token = map_text(&token, |text| text.nfc().collect()),
+            Form::Nfkc => token = map_text(&token, |text| text.nfkc().collect()),
+        }
+
+        match self.case {
+            Case::Keep => {}
+            Case::Ascii => token.make_ascii_lowercase(),
+            Case::Unicode => token = map_text(&token, str::to_lowercase),
+        }
+
+        if let Some(aliases) = &self.aliases {
+            token = aliases.rewrite(token);
+        }
+
+        if self.stem {
+            token = stem(token);
+        }
+
+        Some((start, token))
+    }
+}
+
+/// range of `token` without leading and trailing punctuation
+fn trim(token: &[u8]) -> (usize, usize) {
+    match std::str::from_utf8(token) {
+        Ok(text) => {
+            let start = text.len() - text.trim_start_matches(|c: char| !c.is_alphanumeric()).len();
+            let end = text.trim_end_matches(|c: char| !c.is_alphanumeric()).len();
+
+            (start, end.max(start))
+        }
+        Err(_) => {
+            // only ASCII is trimmed around invalid bytes
+            let punct = |byte: &u8| byte.is_ascii() && !byte.is_ascii_alphanumeric();
+
+            let start = token.iter().position(|it| !punct(it)).unwrap_or(token.len());
+            let end = token.iter().rposition(|it| !punct(it)).map_or(start, |it| it + 1);
+
+            (start, end.max(start))
+        }
+    }
+}
+
+/// apply `f` to the valid UTF-8 pieces of `bytes`, invalid bytes are kept
+fn map_text<F: Fn(&str) -> String>(bytes: &[u8], f: F) -> Vec<u8> {
+    let mut out = Vec::with_capacity(bytes.len());
+
+    for chunk in bytes.utf8_chunks() {
+        out.extend_from_slice(f(chunk.valid()).as_bytes());
+        out.extend_from_slice(chunk.invalid());
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Aliases, Case, Form, Normalizer};
+    use std::io::Write;
+    use std::sync::Arc;
+
+    fn key(normalizer: &Normalizer, token: &str) -> Option<(usize, String)> {
+        normalizer
+            .token(token.as_bytes().to_vec())
+            .map(|(start, key)| (start, String::from_utf8(key).unwrap()))
+    }
+
+    #[test]
+    fn test_normalize() {
+        let keep = Normalizer::default();
+        assert_eq!(key(&keep, "\"Word.\""), Some((0, "\"Word.\"".into())));
+
+        let normalizer = Normalizer {
+            strip_cr: true,
+            case: Case::Unicode,
+            form: Form::Nfkc,
+            trim_punctuation: true,
+            ..Normalizer::default()
+        };
+
+        assert_eq!(key(&normalizer, "\"Word.\""), Some((1, "word".into())));
+        assert_eq!(key(&normalizer, "\u{c9}COLE"), Some((0, "\u{e9}cole".into())));
+        // decomposed e and acute accent, and a ligature
+        assert_eq!(key(&normalizer, "E\u{301}cole"), Some((0, "\u{e9}cole".into())));
+        assert_eq!(key(&normalizer, "\u{fb01}n"), Some((0, "fin".into())));
+        assert_eq!(key(&normalizer, "--"), None);
+        assert_eq!(key(&normalizer, ""), Some((0, "".into())));
+
+        let ascii = Normalizer {
+            case: Case::Ascii,
+            ..Normalizer::default()
+        };
+        assert_eq!(key(&ascii, "\u{c9}COLE"), Some((0, "\u{c9}cole".into())));
+
+        let mut line = b"word\r".to_vec();
+        normalizer.line(&mut line);
+        assert_eq!(line, b"word");
+
+        // invalid bytes are kept
+        assert_eq!(normalizer.token(b"(AB\xff)".to_vec()), Some((1, b"ab\xff".to_vec())));
+
+        assert!(!normalizer.surface().changes_spelling());
+        assert!(normalizer.surface().trim_punctuation);
+    }
+
+    #[test]
+    fn test_alias_stem() {
+        let mut tmp = tempfile::NamedTempFile::new().unwrap();
+        writeln!(tmp, "# canonical aliases").unwrap();
+        writeln!(tmp, "run ran").unwrap();
+        writeln!(tmp, "go went gone").unwrap();
+
+        let normalizer = Normalizer {
+            case: Case::Ascii,
+            aliases: Some(Arc::new(Aliases::open(tmp.path()).unwrap())),
+            stem: true,
+            ..Normalizer::default()
+        };
+
+        for word in ["Running", "runs", "ran"] {
+            assert_eq!(key(&normalizer, word), Some((0, "run".into())));
+        }
+        assert_eq!(key(&normalizer, "went"), Some((0, "go".into())));
+
+        assert!(normalizer.changes_spelling());
+        assert!(!normalizer.surface().changes_spelling());
+
+        // same size, another canonical word
+        let edited = Aliases::new(vec![(b"ran".to_vec(), b"walk".to_vec())]);
+        let other = Aliases::new(vec![(b"ran".to_vec(), b"run".to_vec())]);
+        assert_ne!(format!("{:?}", edited), format!("{:?}", other));
+    }
+}
@@ -0,0 +1,218 @@
+use std::ffi::CString;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use crate::v1::io::ReadOptions;
+
+/// bytes read from the head of the source to guess line length
+const SAMPLE_SIZE: u64 = 1024 * 1024;
+
+/// bincode length prefix of a word
+const LEN_PREFIX: u64 = 8;
+
+/// line number and byte offset of a record
+const LOCATION_SIZE: u64 = 16;
+
+/// count field of a v1 record
+const COUNT_SIZE: u64 = 8;
+
+/// a 128-bit fingerprint replaces the word
+const FINGERPRINT_SIZE: u64 = 16;
+
+/// TempEstimate guesses how much temp data a run writes, from a sample of the source.
+///
+/// every word is assumed distinct, so it's an upper bound for inputs with repeats.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TempEstimate {
+    /// words in the whole source
+    pub words: u64,
+    /// bytes of all words
+    pub word_bytes: u64,
+}
+
+impl TempEstimate {
+    /// scale keys of the whole lines in `sample` up to `input_size`, dropped ones aren't spilled
+    pub fn new(input_size: u64, sample: &[u8], options: &ReadOptions) -> Self {
+        let delimiter = &options.delimiter;
+
+        let end = match delimiter.rfind(sample) {
+            Some(end) => end + delimiter.len(),
+            None => {
+                // a single line longer than the sample
+                let words = input_size.min(1);
+
+                return TempEstimate {
+                    words,
+                    word_bytes: input_size - words,
+                };
+            }
+        };
+
+        let mut tokens = Vec::new();
+
+        for line in delimiter.split(&sample[..end - delimiter.len()]) {
+            let mut line = line.to_vec();
+            options.normalizer.line(&mut line);
+            options.tokenizer.tokenize(line, &mut tokens);
+        }
+
+        let keys: Vec<_> = tokens
+            .into_iter()
+            .filter_map(|(_, token)| options.normalizer.token(token))
+            .filter(|(_, key)| options.filter.keep(key))
+            .collect();
+
+        let sample_words = keys.len() as u64;
+        let sample_bytes = keys.iter().map(|it| it.1.len() as u64).sum::<u64>();
+
+        // about one n-gram per token, made of n tokens and the spaces between
+        let n = options.ngram.size.max(1) as u64;
+        let sample_bytes = sample_bytes * n + sample_words * (n - 1);
+
+        TempEstimate {
+            words: (input_size * sample_words).div_ceil(end as u64),
+            word_bytes: input_size * sample_bytes / end as u64,
+        }
+    }
+
+    /// sample the head of `path`
+    pub fn sample<P: AsRef<Path>>(path: P, input_size: u64, options: &ReadOptions) -> io::Result<Self> {
+        let mut sample = Vec::new();
+        File::open(path)?.take(SAMPLE_SIZE).read_to_end(&mut sample)?;
+
+        Ok(TempEstimate::new(input_size, &sample, options))
+    }
+
+    /// most temp bytes alive at the same time
+    ///
+    /// v1 keeps all runs while writing merged ones, v2 keeps a partition
+    /// while writing its resplit parts.
+    pub fn peak(&self, v1: bool, fingerprint: bool) -> u64 {
+        let mut record = LOCATION_SIZE;
+
+        if v1 {
+            record += COUNT_SIZE;
+        }
+
+        let records = if fingerprint {
+            self.words * (record + FINGERPRINT_SIZE)
+        } else {
+            self.words * (record + LEN_PREFIX) + self.word_bytes
+        };
+
+        if v1 {
+            records * 2
+        } else {
+            records + records / 4
+        }
+    }
+}
+
+impl fmt::Display for TempEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "about {} words, {} bytes of words", self.words, self.word_bytes)
+    }
+}
+
+/// bytes an unprivileged user can still write on the filesystem of `dir`
+pub fn available(dir: &Path) -> io::Result<u64> {
+    let path = CString::new(dir.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// like [available], for a directory that may not be created yet: the
+/// nearest existing ancestor is asked, an empty one being the current directory
+pub fn available_at(dir: &Path) -> io::Result<u64> {
+    let mut missing = None;
+
+    for ancestor in dir.ancestors() {
+        let ancestor = if ancestor.as_os_str().is_empty() { Path::new(".") } else { ancestor };
+
+        match available(ancestor) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => missing = Some(err),
+            other => return other,
+        }
+    }
+
+    Err(missing.unwrap_or_else(|| io::ErrorKind::NotFound.into()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{available, available_at, TempEstimate};
+    use crate::filter::Filter;
+    use crate::token::{Ngram, TokenizerKind};
+    use crate::v1::io::ReadOptions;
+
+    #[test]
+    fn test_estimate() {
+        let line = ReadOptions::default();
+
+        let estimate = TempEstimate::new(1000, b"qwer\nasdf\n", &line);
+        assert_eq!(estimate, TempEstimate { words: 200, word_bytes: 800 });
+
+        // 8 byte length, 4 byte word, 16 byte location
+        assert_eq!(estimate.peak(false, false), 200 * 28 * 5 / 4);
+        assert_eq!(estimate.peak(true, false), 200 * 36 * 2);
+        assert_eq!(estimate.peak(false, true), 200 * 32 * 5 / 4);
+
+        // no line end in the sample
+        assert_eq!(TempEstimate::new(1000, b"qwer", &line).words, 1);
+        assert_eq!(TempEstimate::new(0, b"", &line).words, 0);
+
+        let whitespace = ReadOptions {
+            tokenizer: TokenizerKind::Whitespace.build(),
+            ..ReadOptions::default()
+        };
+
+        // partial last line is left out
+        let estimate = TempEstimate::new(1000, b"qw er as\n df\nzx", &whitespace);
+        assert_eq!(estimate, TempEstimate { words: 308, word_bytes: 615 });
+
+        // dropped keys aren't spilled
+        let filtered = ReadOptions {
+            filter: Filter {
+                min_len: 3,
+                ..Filter::default()
+            },
+            ..ReadOptions::default()
+        };
+
+        let estimate = TempEstimate::new(1000, b"qwer\nas\n", &filtered);
+        assert_eq!(estimate, TempEstimate { words: 125, word_bytes: 500 });
+
+        let bigram = ReadOptions {
+            ngram: Ngram {
+                size: 2,
+                across_lines: true,
+            },
+            ..whitespace
+        };
+
+        let estimate = TempEstimate::new(1000, b"qw er as\n df\nzx", &bigram);
+        assert_eq!(estimate, TempEstimate { words: 308, word_bytes: 1538 });
+    }
+
+    #[test]
+    fn test_available() {
+        let dir = tempfile::tempdir().unwrap();
+
+        assert!(available(dir.path()).unwrap() > 0);
+        assert!(available(&dir.path().join("missing")).is_err());
+
+        assert!(available_at(&dir.path().join("new/deeper/st")).unwrap() > 0);
+        assert!(available_at(std::path::Path::new("st-missing")).unwrap() > 0);
+        assert!(available_at(std::path::Path::new("")).unwrap() > 0);
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::error::{Error, ErrorKind, Phase, ResultExt};
+use crate::key::Fingerprint;
+use crate::token::Tokenizer;
+
+/// frequency of a dictionary word without one
+const DEFAULT_FREQ: u64 = 1;
+
+/// CJK ideographs and kana, written without spaces between words
+///
+/// hangul isn't, Korean puts spaces between words.
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30ff}'
+        | '\u{3400}'..='\u{4dbf}'
+        | '\u{4e00}'..='\u{9fff}'
+        | '\u{f900}'..='\u{faff}'
+        | '\u{20000}'..='\u{2ebef}')
+}
+
+/// DictTokenizer segments CJK text by a dictionary of words with frequencies.
+///
+/// every way to cut a run of CJK characters into dictionary words, or
+/// single characters when there's none, is a path of a DAG. the path with
+/// the highest product of word probabilities is taken. other text is split
+/// at Unicode word boundaries like [crate::token::UnicodeTokenizer].
+pub struct DictTokenizer {
+    words: HashMap<String, u64>,
+    /// ln of the sum of all frequencies
+    log_total: f64,
+    /// longest word, in chars
+    max_len: usize,
+}
+
+impl DictTokenizer {
+    /// `words` with their frequencies
+    pub fn new<I: IntoIterator<Item = (String, u64)>>(words: I) -> Self {
+        let words: HashMap<String, u64> = words.into_iter().collect();
+
+        let total = words.values().sum::<u64>().max(1);
+        let max_len = words.keys().map(|it| it.chars().count()).max().unwrap_or(1);
+
+        DictTokenizer {
+            words,
+            log_total: (total as f64).ln(),
+            max_len,
+        }
+    }
+
+    /// load a dictionary file, one `word [frequency]` per line like jieba's,
+    /// anything after the frequency is ignored, `#` starts a comment line
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let reader = BufReader::new(File::open(path).file(path).phase(Phase::Plan)?);
+
+        let mut words = Vec::new();
+
+        for (idx, line) in reader.lines().enumerate() {
+            let line = line.file(path).phase(Phase::Plan)?;
+            let mut fields = line.split_whitespace();
+
+            let word = match fields.next() {
+                Some(word) if !word.starts_with('#') => word,
+                _ => continue,
+            };
+
+            let freq = match fields.next() {
+                Some(freq) => freq.parse::<u64>().map_err(|_| {
+                    let msg = format!("frequency {} on line {} of {}", freq, idx + 1, path.display());
+
+                    Error::new(ErrorKind::InvalidArgument(msg)).with_phase(Phase::Plan)
+                })?,
+                None => DEFAULT_FREQ,
+            };
+
+            words.push((word.to_owned(), freq));
+        }
+
+        Ok(DictTokenizer::new(words))
+    }
+
+    /// cut a run of CJK `text` starting at byte `base` of its line
+    fn segment(&self, text: &str, base: usize, tokens: &mut Vec<(usize, Vec<u8>)>) {
+        // byte offset of every char, and the end
+        let mut bounds: Vec<usize> = text.char_indices().map(|it| it.0).collect();
+        bounds.push(text.len());
+
+        let n = bounds.len() - 1;
+
+        // best log probability of the rest from each char, and where its first word ends
+        let mut route = vec![(0f64, n); n + 1];
+
+        for i in (0..n).rev() {
+            let mut best: Option<(f64, usize)> = None;
+
+            for j in i + 1..=n.min(i + self.max_len) {
+                let freq = match self.words.get(&text[bounds[i]..bounds[j]]) {
+                    Some(freq) => *freq,
+                    // a single char is always a way through
+                    None if j == i + 1 => DEFAULT_FREQ,
+                    None => continue,
+                };
+
+                let score = (freq.max(1) as f64).ln() - self.log_total + route[j].0;
+
+                if best.is_none_or(|it| score > it.0) {
+                    best = Some((score, j));
+                }
+            }
+
+            route[i] = best.unwrap_or((0f64, i + 1));
+        }
+
+        let mut i = 0;
+
+        while i < n {
+            let j = route[i].1;
+            tokens.push((base + bounds[i], text.as_bytes()[bounds[i]..bounds[j]].to_vec()));
+            i = j;
+        }
+    }
+
+    /// words of valid UTF-8 `text`, CJK runs are merged and segmented
+    fn tokenize_text(&self, text: &str, base: usize, tokens: &mut Vec<(usize, Vec<u8>)>) {
+        // byte range of the CJK run being collected
+        let mut run: Option<(usize, usize)> = None;
+
+        for (idx, word) in text.unicode_word_indices() {
+            let cjk = word.chars().all(is_cjk);
+
+            match run {
+                Some((start, end)) if cjk && end == idx => {
+                    run = Some((start, idx + word.len()));
+                    continue;
+                }
+                Some((start, end)) => self.segment(&text[start..end], base + start, tokens),
+                None => {}
+            }
+
+            if cjk {
+                run = Some((idx, idx + word.len()));
+            } else {
+                run = None;
+                tokens.push((base + idx, word.as_bytes().to_vec()));
+            }
+        }
+
+        if let Some((start, end)) = run {
+            self.segment(&text[start..end], base + start, tokens);
+        }
+    }
+}
+
+impl Tokenizer for DictTokenizer {
+    fn tokenize(&self, line: Vec<u8>, tokens: &mut Vec<(usize, Vec<u8>)>) {
+        let mut base = 0;
+
+        for chunk in line.utf8_chunks() {
+            self.tokenize_text(chunk.valid(), base, tokens);
+
+            base += chunk.valid().len() + chunk.invalid().len();
+        }
+    }
+}
+
+impl fmt::Debug for DictTokenizer {
+    /// digest stands for the words, an edited dictionary prints differently
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let entries = self.words.iter().map(|(word, freq)| Fingerprint::entry_bytes(word.as_bytes(), &freq.to_le_bytes()));
+
+        write!(f, "DictTokenizer({} words, {})", self.words.len(), Fingerprint::of_set(entries))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::DictTokenizer;
+    use crate::token::Tokenizer;
+    use std::io::Write;
+
+    fn tokenize(tokenizer: &DictTokenizer, line: &str) -> Vec<(usize, String)> {
+        let mut tokens = Vec::new();
+        tokenizer.tokenize(line.as_bytes().to_vec(), &mut tokens);
+
+        tokens
+            .into_iter()
+            .map(|(start, token)| (start, String::from_utf8(token).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_segment() {
+        let mut tmp = tempfile::NamedTempFile::new().unwrap();
+        writeln!(tmp, "# word frequency").unwrap();
+        writeln!(tmp, "我们 10").unwrap();
+        writeln!(tmp, "学习 10 v").unwrap();
+        writeln!(tmp, "中文 10").unwrap();
+        writeln!(tmp, "分词 10").unwrap();
+        writeln!(tmp, "中文分词 5").unwrap();
+        writeln!(tmp, "词典").unwrap();
+
+        let tokenizer = DictTokenizer::open(tmp.path()).unwrap();
+
+        // one word of frequency 5 beats two of 10 out of 46
+        assert_eq!(
+            tokenize(&tokenizer, "我们学习中文分词, ok 的词典"),
+            vec![
+                (0, "我们".into()),
+                (6, "学习".into()),
+                (12, "中文分词".into()),
+                (26, "ok".into()),
+                (29, "的".into()),
+                (32, "词典".into()),
+            ]
+        );
+
+        // same size, other words
+        let edited = DictTokenizer::new(vec![("我们".to_string(), 10), ("学习".to_string(), 9)]);
+        let other = DictTokenizer::new(vec![("我们".to_string(), 10), ("学习".to_string(), 10)]);
+        assert_ne!(format!("{:?}", edited), format!("{:?}", other));
+
+        writeln!(tmp, "中文 bad").unwrap();
+        assert!(DictTokenizer::open(tmp.path()).is_err());
+    }
+}
@@ -0,0 +1,221 @@
+use std::cell::Cell;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+
+use serde::de::DeserializeOwned;
+
+use crate::error::{Error, ErrorKind};
+
+/// every temp file starts with this magic and its record count
+const MAGIC: [u8; 4] = *b"FNRW";
+
+/// magic, then record count as little endian u64
+pub const HEADER_SIZE: u64 = 12;
+
+thread_local! {
+    /// bytes of temp files not read back yet, the pipeline runs on one thread
+    static LIVE: Cell<u64> = const { Cell::new(0) };
+    static QUOTA: Cell<u64> = const { Cell::new(u64::MAX) };
+}
+
+/// most bytes allowed in temp files at the same time
+pub fn set_quota(bytes: u64) {
+    QUOTA.with(|it| it.set(bytes));
+}
+
+/// account for `bytes` written to temp files, fails once over the quota
+pub fn charge(bytes: u64) -> Result<(), Error> {
+    let live = LIVE.with(|it| {
+        it.set(it.get() + bytes);
+        it.get()
+    });
+    let quota = QUOTA.with(Cell::get);
+
+    if live > quota {
+        return Err(Error::new(ErrorKind::TempQuotaExceeded(quota)));
+    }
+
+    Ok(())
+}
+
+/// temp file is read back, its space is freed with it
+fn release(bytes: u64) {
+    LIVE.with(|it| it.set(it.get().saturating_sub(bytes)));
+}
+
+/// reserve header of a new temp file, count is filled by [finish]
+pub fn begin<W: Write>(writer: &mut W) -> Result<(), Error> {
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&0u64.to_le_bytes())?;
+
+    Ok(())
+}
+
+/// write record count into header and rewind the file for reading
+pub fn finish(file: &mut File, records: u64) -> Result<(), Error> {
+    file.seek(SeekFrom::Start(0))?;
+
+    file.write_all(&MAGIC)?;
+    file.write_all(&records.to_le_bytes())?;
+
+    file.seek(SeekFrom::Start(0))?;
+
+    Ok(())
+}
+
+fn corrupt(reason: &'static str) -> Error {
+    Error::new(ErrorKind::CorruptTempFile(reason))
+}
+
+/// SpillReader reads back exactly the records a temp file was finished with.
+///
+/// clean end is only after the last counted record, a short read inside a
+/// record or any byte after the last one is an error.
+pub struct SpillReader<T> {
+    reader: BufReader<File>,
+    remaining: u64,
+    /// file size, released from temp space on drop
+    len: u64,
+
+    record: PhantomData<T>,
+}
+
+impl<T: DeserializeOwned> SpillReader<T> {
+    pub fn new(file: File, buffer_size: usize) -> Result<Self, Error> {
+        let len = file.metadata()?.len();
+        let mut reader = BufReader::with_capacity(buffer_size, file);
+
+        let mut header = [0u8; HEADER_SIZE as usize];
+        reader.read_exact(&mut header).map_err(|err| match err.kind() {
+            io::ErrorKind::UnexpectedEof => corrupt("missing header"),
+            _ => err.into(),
+        })?;
+
+        if header[..4] != MAGIC {
+            return Err(corrupt("bad header"));
+        }
+
+        let mut count = [0u8; 8];
+        count.copy_from_slice(&header[4..]);
+
+        Ok(SpillReader {
+            reader,
+            remaining: u64::from_le_bytes(count),
+            len,
+            record: PhantomData,
+        })
+    }
+
+    pub fn next_record(&mut self) -> Result<Option<T>, Error> {
+        if self.remaining == 0 {
+            if !self.reader.fill_buf()?.is_empty() {
+                return Err(corrupt("data after last record"));
+            }
+
+            return Ok(None);
+        }
+
+        let record = bincode::deserialize_from(&mut self.reader).map_err(|err| match *err {
+            bincode::ErrorKind::Io(ref io) if io.kind() == io::ErrorKind::UnexpectedEof => {
+                corrupt("truncated record")
+            }
+            _ => Error::from(err),
+        })?;
+
+        self.remaining -= 1;
+
+        Ok(Some(record))
+    }
+}
+
+impl<T> Drop for SpillReader<T> {
+    fn drop(&mut self) {
+        release(self.len);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{begin, charge, finish, set_quota, SpillReader, LIVE};
+    use std::cell::Cell;
+    use std::io::Write;
+
+    fn spill(records: &[u64], count: u64) -> std::fs::File {
+        let mut file = tempfile::tempfile().unwrap();
+
+        begin(&mut file).unwrap();
+        for record in records {
+            bincode::serialize_into(&mut file, &(record.to_string(), *record)).unwrap();
+        }
+        finish(&mut file, count).unwrap();
+
+        file
+    }
+
+    #[test]
+    fn test_read() {
+        let mut reader = SpillReader::<(String, u64)>::new(spill(&[1, 2], 2), 64).unwrap();
+        assert_eq!(reader.remaining, 2);
+
+        assert_eq!(reader.next_record().unwrap(), Some(("1".into(), 1)));
+        assert_eq!(reader.next_record().unwrap(), Some(("2".into(), 2)));
+        assert_eq!(reader.next_record().unwrap(), None);
+    }
+
+    #[test]
+    fn test_truncated() {
+        // header says 3 records, only 2 written
+        let mut reader = SpillReader::<(String, u64)>::new(spill(&[1, 2], 3), 64).unwrap();
+
+        reader.next_record().unwrap();
+        reader.next_record().unwrap();
+        assert!(reader.next_record().is_err());
+
+        // cut in the middle of a record
+        let file = spill(&[1, 2], 2);
+        let len = file.metadata().unwrap().len();
+        file.set_len(len - 3).unwrap();
+
+        let mut reader = SpillReader::<(String, u64)>::new(file, 64).unwrap();
+
+        reader.next_record().unwrap();
+        let err = reader.next_record().unwrap_err();
+        assert!(err.to_string().contains("truncated record"), "{}", err);
+    }
+
+    #[test]
+    fn test_trailing() {
+        let mut file = spill(&[1], 1);
+        file.set_len(0).unwrap();
+
+        // empty file has no header
+        assert!(SpillReader::<(String, u64)>::new(file.try_clone().unwrap(), 64).is_err());
+
+        begin(&mut file).unwrap();
+        bincode::serialize_into(&mut file, &("1".to_string(), 1u64)).unwrap();
+        file.write_all(b"garbage").unwrap();
+        finish(&mut file, 1).unwrap();
+
+        let mut reader = SpillReader::<(String, u64)>::new(file, 64).unwrap();
+        reader.next_record().unwrap();
+        assert!(reader.next_record().is_err());
+    }
+
+    #[test]
+    fn test_quota() {
+        set_quota(100);
+
+        charge(60).unwrap();
+        assert!(charge(60).is_err());
+
+        // reading a file back frees its bytes
+        let file = spill(&[1, 2], 2);
+        let len = file.metadata().unwrap().len();
+        charge(len).unwrap_err();
+
+        let live = LIVE.with(Cell::get);
+        drop(SpillReader::<(String, u64)>::new(file, 64).unwrap());
+        assert_eq!(LIVE.with(Cell::get), live - len);
+    }
+}
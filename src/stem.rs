@@ -0,0 +1,255 @@
+//! Porter stemmer for English words, as in M.F. Porter, "An algorithm for
+//! suffix stripping", 1980.
+//!
+//! only lowercase ASCII words are stemmed, anything else is left as is.
+
+/// `word[i]` is a consonant, `y` is one only after a vowel or at the start
+fn is_consonant(word: &[u8], i: usize) -> bool {
+    match word[i] {
+        b'a' | b'e' | b'i' | b'o' | b'u' => false,
+        b'y' => i == 0 || !is_consonant(word, i - 1),
+        _ => true,
+    }
+}
+
+/// m of `[C](VC){m}[V]`
+fn measure(stem: &[u8]) -> usize {
+    let mut m = 0;
+    let mut vowel = false;
+
+    for i in 0..stem.len() {
+        if is_consonant(stem, i) {
+            if vowel {
+                m += 1;
+            }
+            vowel = false;
+        } else {
+            vowel = true;
+        }
+    }
+
+    m
+}
+
+/// `*v*`
+fn has_vowel(stem: &[u8]) -> bool {
+    (0..stem.len()).any(|i| !is_consonant(stem, i))
+}
+
+/// `*d`
+fn ends_double_consonant(stem: &[u8]) -> bool {
+    let n = stem.len();
+
+    n >= 2 && stem[n - 1] == stem[n - 2] && is_consonant(stem, n - 1)
+}
+
+/// `*o`, consonant-vowel-consonant where the last isn't `w`, `x` or `y`
+fn ends_cvc(stem: &[u8]) -> bool {
+    let n = stem.len();
+
+    n >= 3
+        && is_consonant(stem, n - 3)
+        && !is_consonant(stem, n - 2)
+        && is_consonant(stem, n - 1)
+        && !matches!(stem[n - 1], b'w' | b'x' | b'y')
+}
+
+/// replace the first of `rules` whose suffix `word` ends with, if its stem
+/// passes `cond`. later rules aren't tried once a suffix matches.
+fn replace_suffix(word: &mut Vec<u8>, rules: &[(&str, &str)], cond: fn(&[u8]) -> bool) {
+    for (suffix, replacement) in rules {
+        if word.ends_with(suffix.as_bytes()) {
+            let stem = word.len() - suffix.len();
+
+            if cond(&word[..stem]) {
+                word.truncate(stem);
+                word.extend_from_slice(replacement.as_bytes());
+            }
+
+            return;
+        }
+    }
+}
+
+fn step_1a(word: &mut Vec<u8>) {
+    replace_suffix(word, &[("sses", "ss"), ("ies", "i"), ("ss", "ss"), ("s", "")], |_| true);
+}
+
+fn step_1b(word: &mut Vec<u8>) {
+    if word.ends_with(b"eed") {
+        if measure(&word[..word.len() - 3]) > 0 {
+            word.pop();
+        }
+        return;
+    }
+
+    let stem = if word.ends_with(b"ed") && has_vowel(&word[..word.len() - 2]) {
+        word.len() - 2
+    } else if word.ends_with(b"ing") && has_vowel(&word[..word.len() - 3]) {
+        word.len() - 3
+    } else {
+        return;
+    };
+
+    word.truncate(stem);
+
+    if word.ends_with(b"at") || word.ends_with(b"bl") || word.ends_with(b"iz") {
+        word.push(b'e');
+    } else if ends_double_consonant(word) && !matches!(word[word.len() - 1], b'l' | b's' | b'z') {
+        word.pop();
+    } else if measure(word) == 1 && ends_cvc(word) {
+        word.push(b'e');
+    }
+}
+
+fn step_1c(word: &mut [u8]) {
+    if word.ends_with(b"y") && has_vowel(&word[..word.len() - 1]) {
+        let last = word.len() - 1;
+        word[last] = b'i';
+    }
+}
+
+fn step_2(word: &mut Vec<u8>) {
+    const RULES: &[(&str, &str)] = &[
+        ("ational", "ate"),
+        ("tional", "tion"),
+        ("enci", "ence"),
+        ("anci", "ance"),
+        ("izer", "ize"),
+        ("abli", "able"),
+        ("alli", "al"),
+        ("entli", "ent"),
+        ("eli", "e"),
+        ("ousli", "ous"),
+        ("ization", "ize"),
+        ("ation", "ate"),
+        ("ator", "ate"),
+        ("alism", "al"),
+        ("iveness", "ive"),
+        ("fulness", "ful"),
+        ("ousness", "ous"),
+        ("aliti", "al"),
+        ("iviti", "ive"),
+        ("biliti", "ble"),
+    ];
+
+    replace_suffix(word, RULES, |stem| measure(stem) > 0);
+}
+
+fn step_3(word: &mut Vec<u8>) {
+    const RULES: &[(&str, &str)] = &[
+        ("icate", "ic"),
+        ("ative", ""),
+        ("alize", "al"),
+        ("iciti", "ic"),
+        ("ical", "ic"),
+        ("ful", ""),
+        ("ness", ""),
+    ];
+
+    replace_suffix(word, RULES, |stem| measure(stem) > 0);
+}
+
+fn step_4(word: &mut Vec<u8>) {
+    const RULES: &[(&str, &str)] = &[
+        ("al", ""),
+        ("ance", ""),
+        ("ence", ""),
+        ("er", ""),
+        ("ic", ""),
+        ("able", ""),
+        ("ible", ""),
+        ("ant", ""),
+        ("ement", ""),
+        ("ment", ""),
+        ("ent", ""),
+        ("ion", ""),
+        ("ou", ""),
+        ("ism", ""),
+        ("ate", ""),
+        ("iti", ""),
+        ("ous", ""),
+        ("ive", ""),
+        ("ize", ""),
+    ];
+
+    // `ion` only after `s` or `t`
+    if word.ends_with(b"ion") {
+        let stem = &word[..word.len() - 3];
+
+        if measure(stem) > 1 && matches!(stem.last(), Some(b's') | Some(b't')) {
+            word.truncate(stem.len());
+        }
+        return;
+    }
+
+    replace_suffix(word, RULES, |stem| measure(stem) > 1);
+}
+
+fn step_5(word: &mut Vec<u8>) {
+    if word.ends_with(b"e") {
+        let stem = &word[..word.len() - 1];
+        let m = measure(stem);
+
+        if m > 1 || (m == 1 && !ends_cvc(stem)) {
+            word.pop();
+        }
+    }
+
+    if word.ends_with(b"ll") && measure(word) > 1 {
+        word.pop();
+    }
+}
+
+/// stem of an English word
+pub fn stem(mut word: Vec<u8>) -> Vec<u8> {
+    if word.len() <= 2 || !word.iter().all(u8::is_ascii_lowercase) {
+        return word;
+    }
+
+    step_1a(&mut word);
+    step_1b(&mut word);
+    step_1c(&mut word);
+    step_2(&mut word);
+    step_3(&mut word);
+    step_4(&mut word);
+    step_5(&mut word);
+
+    word
+}
+
+#[cfg(test)]
+mod test {
+    use super::stem;
+
+    fn check(word: &str, expected: &str) {
+        assert_eq!(stem(word.as_bytes().to_vec()), expected.as_bytes(), "{}", word);
+    }
+
+    #[test]
+    fn test_stem() {
+        check("caresses", "caress");
+        check("ponies", "poni");
+        check("cats", "cat");
+        check("feed", "feed");
+        check("plastered", "plaster");
+        check("motoring", "motor");
+        check("sing", "sing");
+        check("hopping", "hop");
+        check("falling", "fall");
+        check("filing", "file");
+        check("happy", "happi");
+        check("relational", "relat");
+        check("generalization", "gener");
+        check("connection", "connect");
+        check("connected", "connect");
+        check("running", "run");
+        check("runs", "run");
+        check("controll", "control");
+
+        // left as is
+        check("is", "is");
+        check("Running", "Running");
+        check("caf\u{e9}s", "caf\u{e9}s");
+    }
+}
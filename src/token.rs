@@ -0,0 +1,567 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use regex::bytes::Regex;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::error::{Error, ErrorKind, Phase};
+use crate::filter::Filter;
+use crate::normalize::Normalizer;
+use crate::v1::io::{ChunkError, ChunkFile, Location, ReadOptions};
+
+/// Tokenizer splits a line into the words that are counted.
+///
+/// a token is pushed with the byte offset it starts at in the line, lines
+/// come in already checked by the reader's encoding.
+pub trait Tokenizer: fmt::Debug + Send + Sync {
+    fn tokenize(&self, line: Vec<u8>, tokens: &mut Vec<(usize, Vec<u8>)>);
+
+    /// keys are located where their tokens start, trimming doesn't move
+    /// them. for tokenizers that can't start reading in the middle of a token
+    fn keeps_start(&self) -> bool {
+        false
+    }
+}
+
+/// whole line is one word, empty lines included
+#[derive(Debug)]
+pub struct LineTokenizer;
+
+impl Tokenizer for LineTokenizer {
+    fn tokenize(&self, line: Vec<u8>, tokens: &mut Vec<(usize, Vec<u8>)>) {
+        tokens.push((0, line));
+    }
+}
+
+/// words separated by ASCII whitespace
+#[derive(Debug)]
+pub struct WhitespaceTokenizer;
+
+impl Tokenizer for WhitespaceTokenizer {
+    fn tokenize(&self, line: Vec<u8>, tokens: &mut Vec<(usize, Vec<u8>)>) {
+        let mut start = None;
+
+        for (idx, byte) in line.iter().enumerate() {
+            match (start, byte.is_ascii_whitespace()) {
+                (None, false) => start = Some(idx),
+                (Some(begin), true) => {
+                    tokens.push((begin, line[begin..idx].to_vec()));
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(begin) = start {
+            tokens.push((begin, line[begin..].to_vec()));
+        }
+    }
+}
+
+/// words by Unicode word boundaries (UAX #29), punctuation and spaces dropped
+///
+/// invalid UTF-8 bytes of bytes mode separate words.
+#[derive(Debug)]
+pub struct UnicodeTokenizer;
+
+impl Tokenizer for UnicodeTokenizer {
+    fn tokenize(&self, line: Vec<u8>, tokens: &mut Vec<(usize, Vec<u8>)>) {
+        let mut base = 0;
+
+        for chunk in line.utf8_chunks() {
+            for (idx, word) in chunk.valid().unicode_word_indices() {
+                tokens.push((base + idx, word.as_bytes().to_vec()));
+            }
+
+            base += chunk.valid().len() + chunk.invalid().len();
+        }
+    }
+}
+
+/// every match of a regex, or a capture group of it, is a word
+///
+/// a token is located at the start of its whole match, the match is found
+/// again reading from there. matches without the group are skipped.
+#[derive(Debug)]
+pub struct RegexTokenizer {
+    regex: Regex,
+    group: usize,
+}
+
+impl RegexTokenizer {
+    /// `group` is a capture group number, 0 for the whole match, or a name
+    pub fn new(pattern: &str, group: &str) -> Result<Self, Error> {
+        let invalid = |msg: String| Error::new(ErrorKind::InvalidArgument(msg)).with_phase(Phase::Plan);
+
+        let regex = Regex::new(pattern).map_err(|err| invalid(format!("--pattern {}: {}", pattern, err)))?;
+
+        let index = match group.parse::<usize>() {
+            Ok(index) => index,
+            Err(_) => regex.capture_names().position(|it| it == Some(group)).unwrap_or(usize::MAX),
+        };
+
+        if index >= regex.captures_len() {
+            return Err(invalid(format!("--group {}: no such group in {}", group, pattern)));
+        }
+
+        Ok(RegexTokenizer { regex, group: index })
+    }
+}
+
+impl Tokenizer for RegexTokenizer {
+    fn tokenize(&self, line: Vec<u8>, tokens: &mut Vec<(usize, Vec<u8>)>) {
+        for captures in self.regex.captures_iter(&line) {
+            if let Some(group) = captures.get(self.group) {
+                tokens.push((captures.get(0).unwrap().start(), group.as_bytes().to_vec()));
+            }
+        }
+    }
+
+    fn keeps_start(&self) -> bool {
+        true
+    }
+}
+
+/// tokenizer chosen by name on the command line
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TokenizerKind {
+    Line,
+    Whitespace,
+    Unicode,
+}
+
+impl TokenizerKind {
+    pub fn build(self) -> Arc<dyn Tokenizer> {
+        match self {
+            TokenizerKind::Line => Arc::new(LineTokenizer),
+            TokenizerKind::Whitespace => Arc::new(WhitespaceTokenizer),
+            TokenizerKind::Unicode => Arc::new(UnicodeTokenizer),
+        }
+    }
+}
+
+impl FromStr for TokenizerKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "line" => Ok(TokenizerKind::Line),
+            "whitespace" => Ok(TokenizerKind::Whitespace),
+            "unicode" => Ok(TokenizerKind::Unicode),
+            _ => Err(()),
+        }
+    }
+}
+
+/// keys made of `size` tokens in a row, joined by a space
+///
+/// a key is located at its first token. tokens are joined after they're
+/// normalized and filtered.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Ngram {
+    /// 1 is a key per token
+    pub size: usize,
+    /// a key may take tokens of the following lines
+    pub across_lines: bool,
+}
+
+impl Default for Ngram {
+    fn default() -> Self {
+        Ngram {
+            size: 1,
+            across_lines: false,
+        }
+    }
+}
+
+/// TokenReader yields tokens of a source file with their exact location.
+///
+/// it's driven like [ChunkFile]: on [ChunkError::NextChunk] call
+/// [TokenReader::load_chunk] and ask again.
+pub struct TokenReader {
+    inner: ChunkFile,
+    tokenizer: Arc<dyn Tokenizer>,
+    normalizer: Normalizer,
+    filter: Filter,
+    ngram: Ngram,
+    /// tokens are returned as written, see [TokenReader::with_surface]
+    surface: Option<Normalizer>,
+
+    /// tokens of the current line before normalization
+    tokens: Vec<(usize, Vec<u8>)>,
+    /// keys of the current line, last one first
+    pending: Vec<(usize, Vec<u8>)>,
+    /// location of the current line
+    line: Location,
+    /// next token is the first of its line
+    fresh: bool,
+    /// start of the line the last key starts, if it's the first one of it
+    start: Option<Location>,
+    /// tokens of the next n-gram, with their location and [TokenReader::line_start]
+    window: VecDeque<(Vec<u8>, Location, Option<Location>)>,
+}
+
+impl TokenReader {
+    /// split lines of `inner` by the tokenizer and normalizer of `options`
+    pub fn new(inner: ChunkFile, options: &ReadOptions) -> Self {
+        TokenReader {
+            inner,
+            tokenizer: options.tokenizer.clone(),
+            normalizer: options.normalizer.clone(),
+            filter: options.filter.clone(),
+            ngram: options.ngram,
+            surface: None,
+            tokens: Vec::new(),
+            pending: Vec::new(),
+            line: Location::default(),
+            fresh: false,
+            start: None,
+            window: VecDeque::new(),
+        }
+    }
+
+    /// return tokens as written in the source, only trimmed like their keys.
+    /// tokens are still dropped by their keys
+    pub fn with_surface(mut self) -> Self {
+        self.surface = Some(self.normalizer.surface());
+        self
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P, options: &ReadOptions) -> Result<Self, Error> {
+        let inner = ChunkFile::open(path, options)?;
+
+        Ok(TokenReader::new(inner, options))
+    }
+
+    pub fn load_chunk(&mut self) -> Result<usize, Error> {
+        self.inner.load_chunk()
+    }
+
+    /// continue reading at `location`, the rest of its line is tokenized from there
+    pub fn seek(&mut self, location: Location) -> Result<(), Error> {
+        self.pending.clear();
+        self.window.clear();
+        self.inner.seek(location)
+    }
+
+    /// start of the line, if the last key starts with the first token of it
+    ///
+    /// a checkpoint taken there is resumed with the whole line.
+    pub fn line_start(&self) -> Option<Location> {
+        self.start
+    }
+
+    /// next key and the location its first token starts at, lines without tokens are skipped
+    pub fn next_token(&mut self) -> Result<(Vec<u8>, Location), ChunkError> {
+        let size = self.ngram.size;
+
+        if size <= 1 {
+            return self.next_key();
+        }
+
+        loop {
+            if self.window.len() == size {
+                let (mut key, location, start) = self.window.pop_front().unwrap();
+
+                for (token, _, _) in &self.window {
+                    key.push(b' ');
+                    key.extend_from_slice(token);
+                }
+
+                self.start = start;
+
+                return Ok((key, location));
+            }
+
+            match self.next_key() {
+                Ok((token, location)) => {
+                    if self.start.is_some() && !self.ngram.across_lines {
+                        self.window.clear();
+                    }
+
+                    self.window.push_back((token, location, self.start));
+                }
+                // tokens of the lines before aren't joined yet, resume from the first of them
+                Err(ChunkError::Cancelled(location)) if self.ngram.across_lines => {
+                    let location = self.window.front().map_or(location, |it| it.1);
+
+                    return Err(ChunkError::Cancelled(location));
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// next key of a single token
+    fn next_key(&mut self) -> Result<(Vec<u8>, Location), ChunkError> {
+        loop {
+            if let Some((start, token)) = self.pending.pop() {
+                let first = std::mem::replace(&mut self.fresh, false);
+                self.start = if first { Some(self.line) } else { None };
+
+                return Ok((token, Location::new(self.line.offset + start as u64, self.line.line)));
+            }
+
+            let (mut line, location) = self.inner.next_word()?;
+
+            if self.inner.is_long() {
+                // only the key of a too long line is kept, it can't be split
+                if self.filter.keep_long(&line) {
+                    self.pending.push((0, line));
+                }
+            } else {
+                self.normalizer.line(&mut line);
+                self.tokenizer.tokenize(line, &mut self.tokens);
+                let keeps_start = self.tokenizer.keeps_start();
+
+                for (start, token) in self.tokens.drain(..).rev() {
+                    let surface = self.surface.as_ref().and_then(|it| it.token(token.clone()));
+
+                    match self.normalizer.token(token) {
+                        Some((trimmed, key)) if self.filter.keep(&key) => {
+                            let start = if keeps_start { start } else { start + trimmed };
+                            // trimmed the same way, it's there when the key is
+                            let key = surface.map_or(key, |it| it.1);
+
+                            self.pending.push((start, key));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+
+            self.line = location;
+            self.fresh = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Ngram, RegexTokenizer, Tokenizer, TokenizerKind, TokenReader};
+    use crate::cancel::CancelToken;
+    use crate::filter::Filter;
+    use crate::normalize::Normalizer;
+    use regex::bytes::Regex;
+    use std::sync::Arc;
+    use crate::v1::io::{ChunkError, Location, ReadOptions};
+    use std::io::Write;
+
+    fn tokenize(kind: TokenizerKind, line: &[u8]) -> Vec<(usize, Vec<u8>)> {
+        let mut tokens = Vec::new();
+        kind.build().tokenize(line.to_vec(), &mut tokens);
+
+        tokens
+    }
+
+    #[test]
+    fn test_tokenizer() {
+        assert_eq!(tokenize(TokenizerKind::Line, b" qwer  asdf"), vec![(0, b" qwer  asdf".to_vec())]);
+        assert_eq!(tokenize(TokenizerKind::Line, b""), vec![(0, b"".to_vec())]);
+
+        assert_eq!(
+            tokenize(TokenizerKind::Whitespace, b" qwer\t asdf"),
+            vec![(1, b"qwer".to_vec()), (7, b"asdf".to_vec())]
+        );
+        assert!(tokenize(TokenizerKind::Whitespace, b"  ").is_empty());
+
+        assert_eq!(
+            tokenize(TokenizerKind::Unicode, "it's a \u{e9}t\u{e9}, ok.".as_bytes()),
+            vec![
+                (0, b"it's".to_vec()),
+                (5, b"a".to_vec()),
+                (7, "\u{e9}t\u{e9}".as_bytes().to_vec()),
+                (14, b"ok".to_vec())
+            ]
+        );
+
+        // invalid byte separates words
+        assert_eq!(
+            tokenize(TokenizerKind::Unicode, b"ab\xffcd"),
+            vec![(0, b"ab".to_vec()), (3, b"cd".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_regex() {
+        let tokenize = |tokenizer: RegexTokenizer, line: &[u8]| {
+            let mut tokens = Vec::new();
+            tokenizer.tokenize(line.to_vec(), &mut tokens);
+
+            tokens
+        };
+
+        let ip = RegexTokenizer::new(r"\d+\.\d+\.\d+\.\d+", "0").unwrap();
+        assert_eq!(
+            tokenize(ip, b"from 10.0.0.1 to 10.0.0.2"),
+            vec![(5, b"10.0.0.1".to_vec()), (17, b"10.0.0.2".to_vec())]
+        );
+
+        // located at the whole match
+        let id = RegexTokenizer::new(r"id=(?P<id>\w+)", "id").unwrap();
+        assert_eq!(tokenize(id, b"a id=x1 b id=y2"), vec![(2, b"x1".to_vec()), (10, b"y2".to_vec())]);
+
+        let optional = RegexTokenizer::new("(a)|b", "1").unwrap();
+        assert_eq!(tokenize(optional, b"ab"), vec![(0, b"a".to_vec())]);
+
+        assert!(RegexTokenizer::new("(", "0").is_err());
+        assert!(RegexTokenizer::new("(a)", "2").is_err());
+        assert!(RegexTokenizer::new("(a)", "name").is_err());
+
+        let mut tmp = tempfile::NamedTempFile::new().unwrap();
+        tmp.write_all(b"say \"hi\" and \"bye\"\n").unwrap();
+
+        let options = ReadOptions {
+            tokenizer: Arc::new(RegexTokenizer::new("\"[^\"]*\"", "0").unwrap()),
+            normalizer: Normalizer {
+                trim_punctuation: true,
+                ..Normalizer::default()
+            },
+            ..ReadOptions::default()
+        };
+
+        // trimming doesn't move a key off its match
+        let mut reader = TokenReader::open(tmp.path(), &options).unwrap();
+        let keys = read_all(&mut reader);
+        assert_eq!(keys[1], (b"bye".to_vec(), Location::new(13, 1), None));
+
+        reader.seek(Location::new(13, 1)).unwrap();
+        assert_eq!(reader.next_token().unwrap(), (b"bye".to_vec(), Location::new(13, 1)));
+    }
+
+    /// keys with their location and line start
+    fn read_all(reader: &mut TokenReader) -> Vec<(Vec<u8>, Location, Option<Location>)> {
+        let mut tokens = Vec::new();
+
+        loop {
+            match reader.next_token() {
+                Ok((token, location)) => tokens.push((token, location, reader.line_start())),
+                Err(ChunkError::NextChunk) => {
+                    reader.load_chunk().unwrap();
+                }
+                Err(ChunkError::Eof) => break,
+                Err(err) => panic!("{}", err),
+            }
+        }
+
+        tokens
+    }
+
+    #[test]
+    fn test_reader() {
+        let mut tmp = tempfile::NamedTempFile::new().unwrap();
+        tmp.write_all(b"qwer asdf\n\n  zxcv\n").unwrap();
+
+        let options = ReadOptions {
+            tokenizer: TokenizerKind::Whitespace.build(),
+            ..ReadOptions::default()
+        };
+
+        let mut reader = TokenReader::open(tmp.path(), &options).unwrap();
+
+        assert_eq!(
+            read_all(&mut reader),
+            vec![
+                (b"qwer".to_vec(), Location::new(0, 1), Some(Location::new(0, 1))),
+                (b"asdf".to_vec(), Location::new(5, 1), None),
+                (b"zxcv".to_vec(), Location::new(13, 3), Some(Location::new(11, 3))),
+            ]
+        );
+
+        // a token is read again from its own offset
+        reader.seek(Location::new(5, 1)).unwrap();
+        assert_eq!(reader.next_token().unwrap(), (b"asdf".to_vec(), Location::new(5, 1)));
+    }
+
+    #[test]
+    fn test_long_filtered() {
+        let mut tmp = tempfile::NamedTempFile::new().unwrap();
+        writeln!(tmp, "error {}", "x".repeat(100)).unwrap();
+        writeln!(tmp, "warn {}", "x".repeat(100)).unwrap();
+        writeln!(tmp, "error").unwrap();
+
+        let options = ReadOptions {
+            max_word: 16,
+            filter: Filter {
+                exclude: Some(Regex::new("^warn").unwrap()),
+                ..Filter::default()
+            },
+            ..ReadOptions::default()
+        };
+
+        let mut reader = TokenReader::open(tmp.path(), &options).unwrap();
+        let tokens = read_all(&mut reader);
+
+        // the long line starting with "warn" is excluded by its key's head
+        assert_eq!(tokens.len(), 2);
+        assert!(tokens[0].0.starts_with(b"error xxx"));
+        assert_eq!(tokens[1].0, b"error".to_vec());
+    }
+
+    #[test]
+    fn test_ngram() {
+        let mut tmp = tempfile::NamedTempFile::new().unwrap();
+        tmp.write_all(b"a b c\nd\ne f\n").unwrap();
+
+        let options = ReadOptions {
+            tokenizer: TokenizerKind::Whitespace.build(),
+            ngram: Ngram {
+                size: 2,
+                across_lines: false,
+            },
+            ..ReadOptions::default()
+        };
+
+        let mut reader = TokenReader::open(tmp.path(), &options).unwrap();
+
+        // a line with a single token has no bigram
+        assert_eq!(
+            read_all(&mut reader),
+            vec![
+                (b"a b".to_vec(), Location::new(0, 1), Some(Location::new(0, 1))),
+                (b"b c".to_vec(), Location::new(2, 1), None),
+                (b"e f".to_vec(), Location::new(8, 3), Some(Location::new(8, 3))),
+            ]
+        );
+
+        let across = ReadOptions {
+            ngram: Ngram {
+                size: 2,
+                across_lines: true,
+            },
+            cancel: CancelToken::new(),
+            ..options
+        };
+
+        let mut reader = TokenReader::open(tmp.path(), &across).unwrap();
+
+        assert_eq!(
+            read_all(&mut reader),
+            vec![
+                (b"a b".to_vec(), Location::new(0, 1), Some(Location::new(0, 1))),
+                (b"b c".to_vec(), Location::new(2, 1), None),
+                (b"c d".to_vec(), Location::new(4, 1), None),
+                (b"d e".to_vec(), Location::new(6, 2), Some(Location::new(6, 2))),
+                (b"e f".to_vec(), Location::new(8, 3), Some(Location::new(8, 3))),
+            ]
+        );
+
+        // read again from its first token
+        reader.seek(Location::new(4, 1)).unwrap();
+        assert_eq!(reader.next_token().unwrap(), (b"c d".to_vec(), Location::new(4, 1)));
+
+        // cancelled while `c` waits for the next line, it's resumed from `c`
+        let mut reader = TokenReader::open(tmp.path(), &across).unwrap();
+        reader.next_token().unwrap();
+        reader.next_token().unwrap();
+        across.cancel.cancel();
+
+        match reader.next_token() {
+            Err(ChunkError::Cancelled(location)) => assert_eq!(location, Location::new(4, 1)),
+            other => panic!("{:?}", other),
+        }
+    }
+}
@@ -1,60 +1,68 @@
 use std::fs::File;
-use std::io::BufReader;
 use std::path::Path;
 
-use failure::Error;
-use bincode::ErrorKind;
+use crate::cancel::CancelToken;
+use crate::error::{Error, ErrorKind, Phase};
 
 use self::count::Counter;
-use self::io::{ChunkError, ChunkFile, DEFAULT_CHUNK_SIZE};
-use self::merge::MergeCounter;
-use crate::v1::count::WordCountOffset;
+use self::io::{ChunkError, Location, ReadOptions};
+use self::merge::{merge_group, merge_runs, MergeCounter};
+use self::plan::MergePlanner;
+use crate::key::WordKey;
+use crate::token::TokenReader;
 
 pub mod count;
 pub mod io;
 pub mod merge;
+pub mod plan;
 
-struct MergePair(String, u64, u64, BufReader<File>);
+pub struct Count<K = String> {
+    io: TokenReader,
 
-struct Count {
-    io: ChunkFile,
+    counter: Counter<K>,
+    merger: MergeCounter<K>,
+    planner: MergePlanner,
+    /// at most `planner.fan_in()` open at the same time
+    chunks: Vec<File>,
+    /// sorted runs flushed so far
+    runs: usize,
 
-    counter: Counter,
-    merger: MergeCounter,
-    chunks: Vec<BufReader<File>>,
+    cancel: CancelToken,
 }
 
-impl Count {
-    pub fn new<P: AsRef<Path>>(path: P) -> Result<Count, Error> {
-        let io = ChunkFile::new(path, DEFAULT_CHUNK_SIZE)?;
+impl<K: WordKey> Count<K> {
+    pub fn new<P: AsRef<Path>>(path: P, options: &ReadOptions, planner: MergePlanner) -> Result<Self, Error> {
+        let io = TokenReader::open(path, options)?;
 
         let counter = Counter::new();
 
         Ok(Count {
             io,
             counter,
-            merger: MergeCounter::new().unwrap(),
+            merger: MergeCounter::new()?,
+            planner,
             chunks: Vec::new(),
+            runs: 0,
+            cancel: options.cancel.clone(),
         })
     }
 
     /// split file into chunk and count part by part
     fn count_chunk(&mut self) -> Result<(), Error> {
         loop {
-            let word = self.io.next_word();
+            let word = self.io.next_token();
 
             match word {
-                Ok((word, offset)) => {
-                    self.counter.count(word, offset);
+                Ok((word, location)) => {
+                    self.counter.count(K::from_word(word), location);
                 }
 
                 Err(ChunkError::NextChunk) => {
                     // flush counter to tmp file
                     let file = self.counter.flush()?;
-                    let reader = BufReader::new(file);
 
                     // and load new chunk
-                    self.chunks.push(reader);
+                    self.push_run(file)?;
 
                     self.io.load_chunk()?;
                 }
@@ -64,69 +72,121 @@ impl Count {
                     break;
                 }
 
-                Err(ChunkError::IoError) => panic!(),
+                Err(ChunkError::Cancelled(location)) => {
+                    let err = Error::new(ErrorKind::Cancelled).with_offset(location.offset);
+
+                    return Err(err.with_phase(Phase::Read));
+                }
+
+                Err(ChunkError::Fatal(err)) => return Err(err),
             }
         }
 
-        // keep handler for merge
         let file = self.counter.flush()?;
-        let reader = BufReader::new(file);
 
-        self.chunks.push(reader);
+        self.push_run(file)
+    }
+
+    /// keep a flushed run, once there are `fan_in` of them they are merged
+    /// into one so that open temp files stay within the fd budget
+    fn push_run(&mut self, file: File) -> Result<(), Error> {
+        self.chunks.push(file);
+        self.runs += 1;
+
+        if self.chunks.len() >= self.planner.fan_in() {
+            let runs = std::mem::take(&mut self.chunks);
+
+            self.chunks.push(merge_group::<K>(runs, &self.planner, &self.cancel)?);
+        }
 
         Ok(())
     }
 
-    /// merge all temp file simultaneously
-    fn merge(&mut self) {
-        let mut queue = Vec::new();
+    /// merge all temp file
+    fn merge(&mut self) -> Result<(), Error> {
+        let chunks = std::mem::take(&mut self.chunks);
 
-        // for each file get first line
-        while let Some(mut file) = self.chunks.pop() {
-            let wco: WordCountOffset = bincode::deserialize_from(&mut file).unwrap();
-            queue.push(MergePair(wco.0, wco.1, wco.2, file));
-        }
+        merge_runs(chunks, &self.planner, &mut self.merger, &self.cancel)
+    }
 
-        loop {
-            // sort files using first line
-            queue.sort_by(|lhs, rhs| rhs.0.cmp(&lhs.0));
-
-            let next = queue.pop();
-
-            match next {
-                Some(pair) => {
-                    // merge
-                    self.merger.count(pair.0, pair.1, pair.2);
-
-                    let mut file = pair.3;
-
-                    let wco: Result<WordCountOffset, Box<ErrorKind>> =
-                        bincode::deserialize_from(&mut file);
-
-                    // enqueue if temp file is not empty
-                    match wco {
-                        Ok(wco) => {
-                            queue.push(MergePair(wco.0, wco.1, wco.2, file));
-                        }
-                        Err(_e) => {
-                            // todo: check eof
-                            continue;
-                        }
-                    }
-                }
-                None => {
-                    // all temp file processed
-                    break;
-                }
-            }
+    /// sorted runs flushed so far, for partial statistics after a cancel
+    pub fn runs(&self) -> usize {
+        self.runs
+    }
+
+    /// first unique word, with its location in source file
+    pub fn solve(&mut self) -> Result<Option<(K, Location)>, Error> {
+        self.count_chunk()?;
+
+        self.merge()?;
+
+        Ok(self.merger.get_ans())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::io::{Location, ReadOptions};
+    use super::plan::MergePlanner;
+    use super::Count;
+    use crate::token::TokenizerKind;
+    use std::io::Write;
+
+    #[test]
+    fn test_tiny_chunks() {
+        let mut tmp = tempfile::NamedTempFile::new().unwrap();
+        tmp.write_all(b"qwer\nab\nqwer\nzxcvb\nab\nlast").unwrap();
+
+        for chunk_size in 6..=16 {
+            let options = ReadOptions {
+                chunk_size,
+                ..ReadOptions::default()
+            };
+
+            let mut count: Count = Count::new(tmp.path(), &options, MergePlanner::default()).unwrap();
+
+            assert_eq!(count.solve().unwrap(), Some(("zxcvb".into(), Location::new(13, 4))));
         }
     }
 
-    pub fn solve(&mut self) -> Option<String> {
-        self.count_chunk().ok()?;
+    #[test]
+    fn test_merge_while_counting() {
+        let mut tmp = tempfile::NamedTempFile::new().unwrap();
+        tmp.write_all(b"qwer
+ab
+qwer
+zxcvb
+ab
+last").unwrap();
+
+        let options = ReadOptions {
+            chunk_size: 6,
+            ..ReadOptions::default()
+        };
+
+        // fan-in 2, every second run is merged right away
+        let planner = MergePlanner::new(0, 2);
+        let mut count: Count = Count::new(tmp.path(), &options, planner).unwrap();
+
+        assert_eq!(count.solve().unwrap(), Some(("zxcvb".into(), Location::new(13, 4))));
+        assert!(count.runs() > 2);
+    }
+
+    #[test]
+    fn test_tokenizer() {
+        let mut tmp = tempfile::NamedTempFile::new().unwrap();
+        tmp.write_all(b"qwer ab qwer\nzxcvb  ab\nlast").unwrap();
 
-        self.merge();
+        for chunk_size in 6..=16 {
+            let options = ReadOptions {
+                chunk_size,
+                tokenizer: TokenizerKind::Whitespace.build(),
+                ..ReadOptions::default()
+            };
 
-        self.merger.get_ans().map(|it| it.0)
+            let mut count: Count = Count::new(tmp.path(), &options, MergePlanner::default()).unwrap();
+
+            assert_eq!(count.solve().unwrap(), Some(("zxcvb".into(), Location::new(13, 2))));
+        }
     }
-}
\ No newline at end of file
+}
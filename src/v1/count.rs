@@ -1,19 +1,32 @@
-use failure::Error;
+use crate::error::{Error, Phase, ResultExt};
 
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use std::fs::File;
-use std::io::{BufWriter, Seek, SeekFrom};
+use std::io::{BufWriter, Write};
 
+use super::io::Location;
+use crate::key::WordKey;
+use crate::spill;
+
+/// word, its count, and where it's first seen
 #[derive(Eq, PartialEq, Serialize, Deserialize, Debug)]
-pub struct WordCountOffset(pub String, pub u64, pub u64);
+pub struct WordCountOffset<K = String>(pub K, pub u64, pub Location);
+
+/// append a record to a run, charged to the temp quota before it's written
+pub fn write_record<K: WordKey, W: Write>(writer: &mut W, wco: &WordCountOffset<K>) -> Result<(), Error> {
+    spill::charge(bincode::serialized_size(wco)?)?;
+    bincode::serialize_into(writer, wco)?;
+
+    Ok(())
+}
 
 /// Counter internal using BTreeMap to count word and keep keys ordered
-pub struct Counter {
-    inner: BTreeMap<String, (u64, u64)>,
+pub struct Counter<K = String> {
+    inner: BTreeMap<K, (u64, Location)>,
 }
 
-impl Counter {
+impl<K: WordKey> Counter<K> {
     pub fn new() -> Self {
         Counter {
             inner: BTreeMap::new(),
@@ -21,18 +34,18 @@ impl Counter {
     }
 
     /// count a word, return the new count
-    pub fn count(&mut self, key: String, offset: u64) -> u64 {
+    pub fn count(&mut self, key: K, location: Location) -> u64 {
         let item = self.inner.get_mut(&key);
 
         match item {
-            Some((count, _offset)) => {
+            Some((count, _location)) => {
                 // offset doesn't need update if it exist.
                 *count += 1;
 
                 *count
             }
             None => {
-                self.inner.insert(key, (1, offset));
+                self.inner.insert(key, (1, location));
                 1
             }
         }
@@ -42,19 +55,21 @@ impl Counter {
     ///
     /// return file handler to temp file
     pub fn flush(&mut self) -> Result<File, Error> {
-        let tmp_file = tempfile::tempfile()?;
+        let tmp_file = tempfile::tempfile().phase(Phase::Count)?;
 
         let mut writer = BufWriter::new(tmp_file);
+        spill::begin(&mut writer).phase(Phase::Count)?;
+        spill::charge(spill::HEADER_SIZE).phase(Phase::Count)?;
 
-        for (key, (count, offset)) in &self.inner {
-            let wco = WordCountOffset(key.clone(), *count, *offset);
+        for (key, (count, location)) in &self.inner {
+            let wco = WordCountOffset(key.clone(), *count, *location);
 
-            bincode::serialize_into(&mut writer, &wco).unwrap();
+            write_record(&mut writer, &wco).phase(Phase::Count)?;
         }
 
-        let mut file = writer.into_inner()?;
-        // reset seek to begin in case for further read
-        file.seek(SeekFrom::Start(0))?;
+        let mut file = writer.into_inner().phase(Phase::Count)?;
+        // record count, and reset seek to begin in case for further read
+        spill::finish(&mut file, self.inner.len() as u64).phase(Phase::Count)?;
         // clear state
         self.inner.clear();
 
@@ -65,35 +80,54 @@ impl Counter {
 #[cfg(test)]
 mod test {
     use super::{Counter, WordCountOffset};
+    use crate::spill::{self, SpillReader};
+    use crate::v1::io::Location;
 
     #[test]
     fn test_count() {
-        let mut counter = Counter::new();
+        let mut counter: Counter = Counter::new();
 
-        assert_eq!(1, counter.count("abcd".into(), 0));
+        assert_eq!(1, counter.count("abcd".into(), Location::new(0, 1)));
 
-        assert_eq!(2, counter.count("abcd".into(), 5));
+        assert_eq!(2, counter.count("abcd".into(), Location::new(5, 2)));
 
-        assert_eq!(1, counter.count("qwer".into(), 10));
+        assert_eq!(1, counter.count("qwer".into(), Location::new(10, 3)));
 
-        assert_eq!(2, counter.count("qwer".into(), 15));
+        assert_eq!(2, counter.count("qwer".into(), Location::new(15, 4)));
     }
 
     #[test]
     fn test_flush() {
-        let mut counter = Counter::new();
-        counter.count("qwer".into(), 0);
-        counter.count("qwer".into(), 5);
+        let mut counter: Counter = Counter::new();
+        counter.count("qwer".into(), Location::new(0, 1));
+        counter.count("qwer".into(), Location::new(5, 2));
+
+        counter.count("zxcv".into(), Location::new(10, 3));
+        counter.count("zxcv".into(), Location::new(15, 4));
 
-        counter.count("zxcv".into(), 10);
-        counter.count("zxcv".into(), 15);
+        let file = counter.flush().unwrap();
+        let mut reader = SpillReader::new(file, 64).unwrap();
 
-        let mut file = counter.flush().unwrap();
+        let wco: Option<WordCountOffset> = reader.next_record().unwrap();
+        assert_eq!(Some(WordCountOffset("qwer".into(), 2, Location::new(0, 1))), wco);
 
-        let wco = bincode::deserialize_from(&mut file).unwrap();
-        assert_eq!(WordCountOffset("qwer".into(), 2, 0), wco);
+        let wco: Option<WordCountOffset> = reader.next_record().unwrap();
+        assert_eq!(Some(WordCountOffset("zxcv".into(), 2, Location::new(10, 3))), wco);
+
+        let wco: Option<WordCountOffset> = reader.next_record().unwrap();
+        assert_eq!(None, wco);
+    }
+
+    #[test]
+    fn test_flush_quota() {
+        let mut counter: Counter = Counter::new();
+        for idx in 0..100 {
+            counter.count(format!("word{}", idx), Location::new(idx, idx));
+        }
 
-        let wco = bincode::deserialize_from(&mut file).unwrap();
-        assert_eq!(WordCountOffset("zxcv".into(), 2, 10), wco);
+        // charged record by record, not after the whole run is written
+        spill::set_quota(spill::HEADER_SIZE + 40);
+        let err = counter.flush().unwrap_err();
+        assert!(err.to_string().contains("--max-temp-bytes"));
     }
 }
@@ -1,178 +1,780 @@
-use failure::Error;
-use failure::Fail;
-
+use std::fmt;
 use std::fs::File;
 use std::io::prelude::*;
+use std::io::SeekFrom;
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
 
-use std::path::Path;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, ErrorKind, Phase, ResultExt};
+use crate::cancel::CancelToken;
+use crate::filter::Filter;
+use crate::key::FingerprintHasher;
+use crate::normalize::Normalizer;
+use crate::token::{LineTokenizer, Ngram, Tokenizer};
 
 pub const DEFAULT_CHUNK_SIZE: u64 = 1024 * 1024 * 1024;
 
+/// words longer than this are counted by fingerprint instead of their bytes
+pub const DEFAULT_MAX_WORD: usize = 16 * 1024 * 1024;
+
+/// bytes of a long word kept in its key, so that it can still be printed
+const LONG_WORD_HEAD: usize = 64;
+
+/// how bytes that are not valid UTF-8 are handled
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Encoding {
+    /// fail on the first invalid byte
+    Strict,
+    /// replace invalid sequences with U+FFFD
+    Lossy,
+    /// words are raw byte strings, nothing is checked
+    Bytes,
+}
+
+impl Encoding {
+    /// decode a raw word, on failure return index of the first invalid byte
+    pub fn decode(self, word: Vec<u8>) -> Result<Vec<u8>, usize> {
+        match self {
+            Encoding::Bytes => Ok(word),
+            Encoding::Strict => match std::str::from_utf8(&word) {
+                Ok(_) => Ok(word),
+                Err(err) => Err(err.valid_up_to()),
+            },
+            Encoding::Lossy => match String::from_utf8(word) {
+                Ok(word) => Ok(word.into_bytes()),
+                Err(err) => Ok(String::from_utf8_lossy(err.as_bytes()).into_owned().into_bytes()),
+            },
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "strict" => Ok(Encoding::Strict),
+            "lossy" => Ok(Encoding::Lossy),
+            "bytes" => Ok(Encoding::Bytes),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Delimiter ends every record of a source file, `\n` by default.
+///
+/// it's any non-empty byte sequence, written with `\n`, `\r`, `\t`, `\0`,
+/// `\\` and `\xNN` escapes on the command line.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Delimiter(Vec<u8>);
+
+impl Delimiter {
+    pub fn new(bytes: Vec<u8>) -> Option<Self> {
+        if bytes.is_empty() {
+            None
+        } else {
+            Some(Delimiter(bytes))
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// index of the first delimiter in `bytes`
+    pub fn find(&self, bytes: &[u8]) -> Option<usize> {
+        match self.0[..] {
+            [byte] => bytes.iter().position(|it| *it == byte),
+            _ => bytes.windows(self.0.len()).position(|it| it == &self.0[..]),
+        }
+    }
+
+    /// index of the last delimiter in `bytes`
+    pub fn rfind(&self, bytes: &[u8]) -> Option<usize> {
+        match self.0[..] {
+            [byte] => bytes.iter().rposition(|it| *it == byte),
+            _ => bytes.windows(self.0.len()).rposition(|it| it == &self.0[..]),
+        }
+    }
+
+    /// records of `bytes`, the last one ends at the end of `bytes`
+    pub fn split<'a>(&'a self, mut bytes: &'a [u8]) -> impl Iterator<Item = &'a [u8]> + 'a {
+        let mut done = false;
+
+        std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+
+            match self.find(bytes) {
+                Some(idx) => {
+                    let record = &bytes[..idx];
+                    bytes = &bytes[idx + self.0.len()..];
+
+                    Some(record)
+                }
+                None => {
+                    done = true;
+
+                    Some(bytes)
+                }
+            }
+        })
+    }
+}
+
+impl Default for Delimiter {
+    fn default() -> Self {
+        Delimiter(vec![b'\n'])
+    }
+}
+
+impl FromStr for Delimiter {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let mut bytes = Vec::new();
+        let mut rest = s.as_bytes();
+
+        while let Some((&byte, tail)) = rest.split_first() {
+            rest = tail;
+
+            if byte != b'\\' {
+                bytes.push(byte);
+                continue;
+            }
+
+            let (&escape, tail) = rest.split_first().ok_or(())?;
+            rest = tail;
+
+            bytes.push(match escape {
+                b'n' => b'\n',
+                b'r' => b'\r',
+                b't' => b'\t',
+                b'0' => 0,
+                b'\\' => b'\\',
+                b'x' if rest.len() >= 2 => {
+                    let hex = std::str::from_utf8(&rest[..2]).map_err(|_| ())?;
+                    rest = &rest[2..];
+
+                    u8::from_str_radix(hex, 16).map_err(|_| ())?
+                }
+                _ => return Err(()),
+            });
+        }
+
+        Delimiter::new(bytes).ok_or(())
+    }
+}
+
+/// how a source file is read into words
+#[derive(Clone, Debug)]
+pub struct ReadOptions {
+    pub chunk_size: u64,
+    pub encoding: Encoding,
+    pub max_word: usize,
+    pub delimiter: Delimiter,
+    /// checked before every word
+    pub cancel: CancelToken,
+    /// splits lines into words, by [crate::token::TokenReader]
+    pub tokenizer: Arc<dyn Tokenizer>,
+    /// turns words into keys, by [crate::token::TokenReader]
+    pub normalizer: Normalizer,
+    /// drops keys, by [crate::token::TokenReader]
+    pub filter: Filter,
+    /// joins tokens into keys, by [crate::token::TokenReader]
+    pub ngram: Ngram,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        ReadOptions {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            encoding: Encoding::Strict,
+            max_word: DEFAULT_MAX_WORD,
+            delimiter: Delimiter::default(),
+            cancel: CancelToken::new(),
+            tokenizer: Arc::new(LineTokenizer),
+            normalizer: Normalizer::default(),
+            filter: Filter::default(),
+            ngram: Ngram::default(),
+        }
+    }
+}
+
+/// UTF-8 check of a byte stream fed piece by piece
+#[derive(Default)]
+struct Utf8Check {
+    /// unfinished sequence at the end of the last piece
+    carry: Vec<u8>,
+    /// bytes checked so far
+    pos: u64,
+    bad: Option<u64>,
+}
+
+impl Utf8Check {
+    fn push(&mut self, mut bytes: &[u8]) {
+        if self.bad.is_some() {
+            return;
+        }
+
+        // finish the sequence cut by the previous piece, at most 3 more bytes
+        while !self.carry.is_empty() && !bytes.is_empty() {
+            self.carry.push(bytes[0]);
+            bytes = &bytes[1..];
+
+            match std::str::from_utf8(&self.carry) {
+                Ok(_) => {
+                    self.pos += self.carry.len() as u64;
+                    self.carry.clear();
+                }
+                Err(err) if err.error_len().is_some() => {
+                    self.bad = Some(self.pos);
+                    return;
+                }
+                Err(_) => {}
+            }
+        }
+
+        match std::str::from_utf8(bytes) {
+            Ok(_) => self.pos += bytes.len() as u64,
+            Err(err) => {
+                let valid = err.valid_up_to();
+
+                if err.error_len().is_some() {
+                    self.bad = Some(self.pos + valid as u64);
+                } else {
+                    self.pos += valid as u64;
+                    self.carry = bytes[valid..].to_vec();
+                }
+            }
+        }
+    }
+
+    /// position of the first invalid byte, if any
+    fn finish(&self) -> Option<u64> {
+        match self.bad {
+            Some(bad) => Some(bad),
+            None if !self.carry.is_empty() => Some(self.pos),
+            None => None,
+        }
+    }
+}
+
+/// a line that doesn't fit in the chunk buffer
+///
+/// up to `max_word` bytes are kept, past that only its first bytes, length
+/// and fingerprint are, so memory stays bounded for any line length.
+#[derive(Default)]
+struct PartialLine {
+    head: Vec<u8>,
+    len: u64,
+    hasher: FingerprintHasher,
+    /// only in strict mode
+    check: Option<Utf8Check>,
+}
+
+impl PartialLine {
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn is_long(&self, max_word: usize) -> bool {
+        self.len > max_word as u64
+    }
+
+    fn push(&mut self, bytes: &[u8], max_word: usize, encoding: Encoding) {
+        if encoding == Encoding::Strict {
+            self.check.get_or_insert_with(Utf8Check::default).push(bytes);
+        }
+
+        if !self.is_long(max_word) {
+            let room = max_word - self.head.len();
+            self.head.extend_from_slice(&bytes[..room.min(bytes.len())]);
+        }
+
+        self.hasher.write(bytes);
+        self.len += bytes.len() as u64;
+
+        if self.is_long(max_word) && self.head.len() > LONG_WORD_HEAD {
+            self.head.truncate(LONG_WORD_HEAD);
+            self.head.shrink_to_fit();
+        }
+    }
+
+    /// key of a long line: its first bytes, length and fingerprint
+    ///
+    /// the key is longer than its head, no real word of the same head can
+    /// equal it unless it spells out the same length and fingerprint.
+    fn long_key(&self, encoding: Encoding) -> Vec<u8> {
+        let mut head = &self.head[..];
+
+        if encoding != Encoding::Bytes {
+            // don't cut a char in half
+            if let Err(err) = std::str::from_utf8(head) {
+                if err.error_len().is_none() {
+                    head = &head[..err.valid_up_to()];
+                }
+            }
+        }
+
+        let mut key = match encoding {
+            Encoding::Lossy => String::from_utf8_lossy(head).into_owned().into_bytes(),
+            _ => head.to_vec(),
+        };
+
+        let fingerprint = self.hasher.finish(self.len);
+        let suffix = format!("\u{2026}[{} bytes, {:016x}{:016x}]", self.len, fingerprint.0, fingerprint.1);
+        key.extend_from_slice(suffix.as_bytes());
+
+        key
+    }
+}
+
+/// where a word starts in the source file
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+pub struct Location {
+    /// absolute byte offset
+    pub offset: u64,
+    /// 1-based line number, records are counted as lines
+    pub line: u64,
+}
+
+impl Location {
+    pub fn new(offset: u64, line: u64) -> Self {
+        Location { offset, line }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, byte offset {}", self.line, self.offset)
+    }
+}
+
 pub struct ChunkFile {
     file: File,
+    /// source path, for error context
+    path: Option<PathBuf>,
+
+    /// absolute offset of `chunk[0]` in the source file
+    base: u64,
+    /// lines returned so far
+    line: u64,
 
     chunk_pos: usize,
+    chunk_size: usize,
+    chunk: Vec<u8>,
 
-    need_read: bool,
-    is_end: bool,
+    /// start of current line, moved out of a full `chunk`
+    partial: PartialLine,
 
-    file_size: u64,
-    load_size: u64,
+    /// a read returned nothing, the rest of the source is in `chunk`
+    is_end: bool,
+    /// last word returned is a long key instead of the line itself
+    long: bool,
 
-    chunk_size: usize,
-    chunk: Vec<u8>,
-    chunk_cap: u64,
+    encoding: Encoding,
+    max_word: usize,
+    delimiter: Delimiter,
+    cancel: CancelToken,
 }
 
-#[derive(Fail, Debug)]
+#[derive(Debug)]
 pub enum ChunkError {
-    #[fail(display = "need next chunk")]
     NextChunk,
-    #[fail(display = "eof")]
     Eof,
+    /// cancelled before the word at this location
+    Cancelled(Location),
 
-    #[fail(display = "")]
-    IoError,
+    Fatal(Error),
+}
+
+impl fmt::Display for ChunkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChunkError::NextChunk => write!(f, "need next chunk"),
+            ChunkError::Eof => write!(f, "eof"),
+            ChunkError::Cancelled(location) => write!(f, "cancelled at {}", location),
+            ChunkError::Fatal(err) => write!(f, "{}", err),
+        }
+    }
 }
 
 impl ChunkFile {
     pub fn new<P: AsRef<Path>>(path: P, chunk_size: u64) -> Result<Self, Error> {
-        let file = File::open(path)?;
+        let path = path.as_ref();
+        let file = File::open(path).file(path).phase(Phase::Read)?;
+
+        let mut chunk_file = ChunkFile::from_file(file, chunk_size).file(path)?;
+        chunk_file.path = Some(path.to_owned());
 
-        ChunkFile::from_file(file, chunk_size)
+        Ok(chunk_file)
     }
 
-    pub fn from_file(file: File, chunk_size: u64) -> Result<Self, Error> {
-        let meta = file.metadata().unwrap();
+    /// open source file with chunk size and encoding from `options`
+    pub fn open<P: AsRef<Path>>(path: P, options: &ReadOptions) -> Result<Self, Error> {
+        let mut chunk_file = ChunkFile::new(path, options.chunk_size)?;
+        chunk_file.encoding = options.encoding;
+        chunk_file.max_word = options.max_word;
+        chunk_file.cancel = options.cancel.clone();
 
+        // a whole delimiter has to fit in the buffer
+        if chunk_file.chunk.len() < options.delimiter.len() {
+            chunk_file.chunk.resize(options.delimiter.len(), 0);
+        }
+        chunk_file.delimiter = options.delimiter.clone();
+
+        Ok(chunk_file)
+    }
+
+    pub fn from_file(file: File, chunk_size: u64) -> Result<Self, Error> {
         let mut chunk_file = ChunkFile {
             file,
+            path: None,
+            base: 0,
+            line: 0,
             chunk_pos: 0,
             chunk_size: 0,
-            need_read: false,
+            chunk: vec![0u8; chunk_size.max(1) as usize],
+            partial: PartialLine::default(),
             is_end: false,
-            file_size: meta.len(),
-            load_size: 0,
-            chunk: vec![0u8; chunk_size as usize],
-            chunk_cap: chunk_size,
+            long: false,
+            encoding: Encoding::Strict,
+            max_word: DEFAULT_MAX_WORD,
+            delimiter: Delimiter::default(),
+            cancel: CancelToken::new(),
         };
 
-        chunk_file.init()?;
+        chunk_file.load_chunk()?;
 
         Ok(chunk_file)
     }
 
-    /// load first chunk into memory
-    fn init(&mut self) -> Result<usize, Error> {
-        let size = self.file.read(&mut self.chunk)?;
+    /// try a new chunk.
+    /// unprocessed bytes are moved to the front, and the read fills after them
+    pub fn load_chunk(&mut self) -> Result<usize, Error> {
+        if self.chunk_pos == 0 && self.chunk_size == self.chunk.len() {
+            // no delimiter in a full chunk, move the line out to make room.
+            // the bytes a delimiter may start with at the end stay
+            let keep = self.delimiter.len() - 1;
+
+            self.partial.push(&self.chunk[..self.chunk_size - keep], self.max_word, self.encoding);
+            self.chunk_pos = self.chunk_size - keep;
+        }
+
+        self.chunk.copy_within(self.chunk_pos..self.chunk_size, 0);
+
+        self.base += self.chunk_pos as u64;
+        self.chunk_size -= self.chunk_pos;
+        self.chunk_pos = 0;
+
+        let size = self.file.read(&mut self.chunk[self.chunk_size..])
+            .map_err(|err| self.error(err.into()))?;
 
-        self.load_size += size as u64;
-        self.chunk_size = size;
-        self.is_end = self.load_size >= self.file_size;
+        self.chunk_size += size;
+
+        if size == 0 && self.chunk_size < self.chunk.len() {
+            self.is_end = true;
+        }
 
         Ok(size)
     }
 
-    /// try a new chunk.
-    /// preserve unprocessed bytes
-    pub fn load_chunk(&mut self) -> Result<usize, ChunkError> {
-        let size = if self.chunk_pos < self.chunk.len() {
-            let remain = self.chunk[self.chunk_pos..self.chunk_size].to_owned();
+    /// attach read phase, source path and current position
+    fn error(&self, err: Error) -> Error {
+        let err = err.with_phase(Phase::Read).with_offset(self.base + self.chunk_size as u64);
 
-            for (idx, c) in remain.into_iter().enumerate() {
-                self.chunk[idx] = c;
-            }
-
-            self.file.read(&mut self.chunk[self.chunk_pos..])
-        } else {
-            self.file.read(&mut self.chunk)
+        match &self.path {
+            Some(path) => err.with_file(path),
+            None => err,
         }
-        .map_err(|_| ChunkError::IoError)?;
+    }
 
-        self.load_size += size as u64;
-        self.chunk_size = size;
+    /// last word is a key made of a too long line, see [PartialLine::long_key]
+    pub fn is_long(&self) -> bool {
+        self.long
+    }
+
+    /// continue reading at `location`, its line number is taken as is
+    pub fn seek(&mut self, location: Location) -> Result<(), Error> {
+        self.file.seek(SeekFrom::Start(location.offset)).map_err(|err| self.error(err.into()))?;
 
+        self.base = location.offset;
+        self.line = location.line.saturating_sub(1);
         self.chunk_pos = 0;
+        self.chunk_size = 0;
+        self.partial = PartialLine::default();
+        self.is_end = false;
 
-        self.is_end = self.load_size >= self.file_size;
+        self.load_chunk()?;
 
-        Ok(size)
+        Ok(())
     }
 
-    /// try read next line, with offset in origin file
-    fn next_line(&mut self) -> Result<(Vec<u8>, u64), ChunkError> {
-        let mut word = Vec::new();
-        let offset = (self.load_size / self.chunk_cap) * self.chunk_cap + self.chunk_pos as u64;
-
-        for &byte in self.chunk[self.chunk_pos..self.chunk_size].iter() {
-            word.push(byte);
+    fn invalid_utf8(&self, offset: u64) -> ChunkError {
+        ChunkError::Fatal(self.error(Error::new(ErrorKind::InvalidUtf8).with_offset(offset)))
+    }
 
-            if byte == b'\n' {
-                break;
-            }
+    fn decode(&self, word: Vec<u8>, location: Location) -> Result<(Vec<u8>, Location), ChunkError> {
+        match self.encoding.decode(word) {
+            Ok(word) => Ok((word, location)),
+            Err(valid_up_to) => Err(self.invalid_utf8(location.offset + valid_up_to as u64)),
         }
-
-        Ok((word, offset as u64))
     }
 
     /// return next `word` in current chunk
-    /// `word` must end with `\n` unless last chunk
+    /// `word` must end with the delimiter unless last chunk
     ///
-    /// if the word is not end with `\n`, a [ChunkError::NextChunk] may return.
+    /// if the word is not end with the delimiter, a [ChunkError::NextChunk] may return.
+    /// its bytes are kept, and read again after [load_chunk]
     ///
-    /// call [next_chunk] to load next chunk into memory
-    pub fn next_word(&mut self) -> Result<(String, u64), ChunkError> {
-        let (mut word, offset) = self.next_line()?;
-
-        self.chunk_pos += word.len();
-
-        if word.is_empty() {
-            if self.is_end {
-                return Err(ChunkError::Eof);
-            } else {
-                self.need_read = true;
-                return Err(ChunkError::NextChunk);
-            }
-        } else {
-            let last = word.last().unwrap();
-
-            if *last == b'\n' {
-                word.pop().unwrap(); // trim
-                return Ok((String::from_utf8(word).unwrap(), offset));
-            } else {
-                // the file may not end with newline, thus this is the last line
-                // otherwise a new chunk is required
-                if self.is_end {
-                    return Ok((String::from_utf8(word).unwrap(), offset));
-                } else {
-                    self.need_read = true;
-                    return Err(ChunkError::NextChunk);
-                }
-            }
+    /// a word longer than `max_word` comes back as a key made of its first
+    /// bytes, length and fingerprint, see [PartialLine::long_key].
+    pub fn next_word(&mut self) -> Result<(Vec<u8>, Location), ChunkError> {
+        let start = self.chunk_pos;
+        let rest = &self.chunk[start..self.chunk_size];
+
+        let offset = self.base + start as u64 - self.partial.len;
+        let location = Location::new(offset, self.line + 1);
+
+        if self.cancel.is_cancelled() {
+            return Err(ChunkError::Cancelled(location));
+        }
+
+        let (len, consumed) = match self.delimiter.find(rest) {
+            Some(idx) => (idx, idx + self.delimiter.len()),
+            None if !self.is_end => return Err(ChunkError::NextChunk),
+            // the file may not end with newline, thus this is the last line
+            None if !rest.is_empty() || !self.partial.is_empty() => (rest.len(), rest.len()),
+            None => return Err(ChunkError::Eof),
+        };
+
+        self.chunk_pos += consumed;
+        self.line += 1;
+        self.long = false;
+
+        if self.partial.is_empty() && len <= self.max_word {
+            let word = self.chunk[start..start + len].to_vec();
+
+            return self.decode(word, location);
         }
+
+        self.partial.push(&self.chunk[start..start + len], self.max_word, self.encoding);
+        let partial = std::mem::take(&mut self.partial);
+
+        if !partial.is_long(self.max_word) {
+            return self.decode(partial.head, location);
+        }
+
+        if let Some(bad) = partial.check.as_ref().and_then(Utf8Check::finish) {
+            return Err(self.invalid_utf8(location.offset + bad));
+        }
+
+        self.long = true;
+
+        Ok((partial.long_key(self.encoding), location))
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::{ChunkFile, DEFAULT_CHUNK_SIZE};
+    use super::{
+        ChunkError, ChunkFile, Delimiter, Encoding, Location, ReadOptions, DEFAULT_CHUNK_SIZE, DEFAULT_MAX_WORD,
+    };
+    use crate::error::Error;
     use std::io::{Seek, SeekFrom, Write};
 
     #[test]
     fn test_word() {
         let mut tmp = tempfile::tempfile().unwrap();
-        tmp.write("qwer\n".as_bytes()).unwrap();
-        tmp.write("abcd\n".as_bytes()).unwrap();
-        tmp.write("zxcv\n".as_bytes()).unwrap();
+        tmp.write_all("qwer\n".as_bytes()).unwrap();
+        tmp.write_all("abcd\n".as_bytes()).unwrap();
+        tmp.write_all("zxcv\n".as_bytes()).unwrap();
+
+        tmp.seek(SeekFrom::Start(0)).unwrap();
+
+        let mut chunk_file = ChunkFile::from_file(tmp, DEFAULT_CHUNK_SIZE).unwrap();
+
+        assert_eq!(chunk_file.next_word().unwrap(), (b"qwer".to_vec(), Location::new(0, 1)));
+
+        assert_eq!(chunk_file.next_word().unwrap(), (b"abcd".to_vec(), Location::new(5, 2)));
+
+        assert_eq!(chunk_file.next_word().unwrap(), (b"zxcv".to_vec(), Location::new(10, 3)));
+    }
+
+    fn read_all(content: &[u8], chunk_size: u64) -> Vec<(Vec<u8>, Location)> {
+        read_with(content, chunk_size, Encoding::Strict, DEFAULT_MAX_WORD).unwrap()
+    }
+
+    fn read_with(
+        content: &[u8],
+        chunk_size: u64,
+        encoding: Encoding,
+        max_word: usize,
+    ) -> Result<Vec<(Vec<u8>, Location)>, Error> {
+        let mut tmp = tempfile::tempfile().unwrap();
+        tmp.write_all(content).unwrap();
+        tmp.seek(SeekFrom::Start(0)).unwrap();
+
+        let mut chunk_file = ChunkFile::from_file(tmp, chunk_size).unwrap();
+        chunk_file.encoding = encoding;
+        chunk_file.max_word = max_word;
+
+        let mut words = Vec::new();
+
+        loop {
+            match chunk_file.next_word() {
+                Ok(word) => words.push(word),
+                Err(ChunkError::NextChunk) => {
+                    chunk_file.load_chunk().unwrap();
+                }
+                Err(ChunkError::Eof) => break,
+                Err(err @ ChunkError::Cancelled(_)) => panic!("{}", err),
+                Err(ChunkError::Fatal(err)) => return Err(err),
+            }
+        }
+
+        Ok(words)
+    }
+
+    #[test]
+    fn test_location() {
+        let content = b"a\nbb\nccc\n\ndddd\nee";
+        let expected = vec![
+            (b"a".to_vec(), Location::new(0, 1)),
+            (b"bb".to_vec(), Location::new(2, 2)),
+            (b"ccc".to_vec(), Location::new(5, 3)),
+            (b"".to_vec(), Location::new(9, 4)),
+            (b"dddd".to_vec(), Location::new(10, 5)),
+            (b"ee".to_vec(), Location::new(15, 6)),
+        ];
+
+        // leftover bytes are carried over at every chunk boundary, lines longer
+        // than the chunk are moved out of it
+        for chunk_size in 1..=32 {
+            assert_eq!(read_all(content, chunk_size), expected, "chunk size {}", chunk_size);
+        }
+    }
 
+    #[test]
+    fn test_delimiter() {
+        assert_eq!("\\0".parse(), Ok(Delimiter::new(vec![0]).unwrap()));
+        assert_eq!("\\r\\n".parse(), Ok(Delimiter::new(b"\r\n".to_vec()).unwrap()));
+        assert_eq!("--\\x2c".parse(), Ok(Delimiter::new(b"--,".to_vec()).unwrap()));
+        assert!("".parse::<Delimiter>().is_err());
+        assert!("\\q".parse::<Delimiter>().is_err());
+
+        let read = |content: &[u8], delimiter: &str, chunk_size: u64| {
+            let mut tmp = tempfile::NamedTempFile::new().unwrap();
+            tmp.write_all(content).unwrap();
+
+            let options = ReadOptions {
+                chunk_size,
+                delimiter: delimiter.parse().unwrap(),
+                ..ReadOptions::default()
+            };
+
+            let mut chunk_file = ChunkFile::open(tmp.path(), &options).unwrap();
+            let mut words = Vec::new();
+
+            loop {
+                match chunk_file.next_word() {
+                    Ok(word) => words.push(word),
+                    Err(ChunkError::NextChunk) => {
+                        chunk_file.load_chunk().unwrap();
+                    }
+                    Err(ChunkError::Eof) => break,
+                    Err(err) => panic!("{}", err),
+                }
+            }
+
+            words
+        };
+
+        // like `find -print0`, newlines are part of a record
+        let expected = vec![
+            (b"a\nb".to_vec(), Location::new(0, 1)),
+            (b"".to_vec(), Location::new(4, 2)),
+            (b"cc".to_vec(), Location::new(5, 3)),
+        ];
+        for chunk_size in 1..=16 {
+            assert_eq!(read(b"a\nb\0\0cc\0", "\\0", chunk_size), expected, "chunk size {}", chunk_size);
+        }
+
+        // a delimiter cut by the end of a chunk is still found
+        let expected = vec![
+            (b"ab".to_vec(), Location::new(0, 1)),
+            (b"c-d".to_vec(), Location::new(5, 2)),
+            (b"e".to_vec(), Location::new(11, 3)),
+        ];
+        for chunk_size in 1..=16 {
+            assert_eq!(read(b"ab---c-d---e", "---", chunk_size), expected, "chunk size {}", chunk_size);
+        }
+    }
+
+    #[test]
+    fn test_encoding() {
+        assert_eq!(Encoding::Strict.decode(b"ab\xffcd".to_vec()), Err(2));
+        assert_eq!(Encoding::Lossy.decode(b"ab\xffcd".to_vec()), Ok("ab\u{fffd}cd".as_bytes().to_vec()));
+        assert_eq!(Encoding::Bytes.decode(b"ab\xffcd".to_vec()), Ok(b"ab\xffcd".to_vec()));
+
+        let mut tmp = tempfile::tempfile().unwrap();
+        tmp.write_all(b"qwer\nab\xffcd\n").unwrap();
         tmp.seek(SeekFrom::Start(0)).unwrap();
 
         let mut chunk_file = ChunkFile::from_file(tmp, DEFAULT_CHUNK_SIZE).unwrap();
 
-        assert_eq!(chunk_file.next_word().unwrap(), ("qwer".to_owned(), 0u64));
+        chunk_file.next_word().unwrap();
+        match chunk_file.next_word() {
+            Err(super::ChunkError::Fatal(err)) => {
+                assert!(err.to_string().contains("at byte offset 7"), "{}", err);
+            }
+            _ => panic!("invalid UTF-8 accepted in strict mode"),
+        }
+    }
+
+    #[test]
+    fn test_long_word() {
+        let long = "x".repeat(100);
+        let other = format!("{}y", "x".repeat(99));
+        let content = format!("{}\nshort\n{}\n{}", long, other, long);
+
+        for chunk_size in [4, 16, 1024] {
+            let words = read_with(content.as_bytes(), chunk_size, Encoding::Strict, 16).unwrap();
+
+            assert_eq!(words.len(), 4);
+            assert_eq!(words[1], (b"short".to_vec(), Location::new(101, 2)));
+            assert_eq!(words[3].1, Location::new(208, 4));
+
+            // same line gives same key, regardless where chunks are cut
+            assert_eq!(words[0].0, words[3].0);
+            assert_ne!(words[0].0, words[2].0);
+
+            let key = String::from_utf8(words[0].0.clone()).unwrap();
+            assert!(key.starts_with(&"x".repeat(16)), "{}", key);
+            assert!(key.contains("[100 bytes, "), "{}", key);
+        }
+    }
+
+    #[test]
+    fn test_long_word_utf8() {
+        // multi-byte chars cut by every chunk boundary
+        let content = format!("{}\nok", "\u{4e2d}".repeat(40));
+        assert!(read_with(content.as_bytes(), 7, Encoding::Strict, 16).is_ok());
+
+        let mut content = "a".repeat(50).into_bytes();
+        content.push(0xff);
+        content.extend_from_slice(b"\nok");
 
-        assert_eq!(chunk_file.next_word().unwrap(), ("abcd".to_owned(), 5u64));
+        let err = read_with(&content, 7, Encoding::Strict, 16).unwrap_err();
+        assert!(err.to_string().contains("at byte offset 50"), "{}", err);
 
-        assert_eq!(chunk_file.next_word().unwrap(), ("zxcv".to_owned(), 10u64));
+        let words = read_with(&content, 7, Encoding::Bytes, 16).unwrap();
+        assert_eq!(words.len(), 2);
     }
 }
@@ -1,15 +1,173 @@
-use failure::Error;
+use crate::error::{Error, Phase, ResultExt};
 
-use super::count::WordCountOffset;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::BufWriter;
+
+use super::count::{write_record, WordCountOffset};
+use super::io::Location;
+use super::plan::MergePlanner;
+use crate::cancel::CancelToken;
+use crate::key::WordKey;
+use crate::spill::{self, SpillReader};
+
+/// head record of a run together with the run's reader
+struct MergePair<K>(WordCountOffset<K>, SpillReader<WordCountOffset<K>>);
+
+impl<K: WordKey> PartialEq for MergePair<K> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<K: WordKey> Eq for MergePair<K> {}
+
+impl<K: WordKey> PartialOrd for MergePair<K> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<K: WordKey> Ord for MergePair<K> {
+    /// reversed, so that `BinaryHeap` pops the smallest word first
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.0).0.cmp(&(self.0).0).then((other.0).2.cmp(&(self.0).2))
+    }
+}
+
+/// RunMerger yields records of several sorted runs ordered by word.
+///
+/// same word from different runs is yielded once per run.
+pub struct RunMerger<K> {
+    queue: BinaryHeap<MergePair<K>>,
+}
+
+impl<K: WordKey> RunMerger<K> {
+    /// open `runs` with a read buffer of `buffer_size` bytes each
+    pub fn new(runs: Vec<File>, buffer_size: usize) -> Result<Self, Error> {
+        let mut queue = BinaryHeap::with_capacity(runs.len());
+
+        for file in runs {
+            let mut reader = SpillReader::new(file, buffer_size).phase(Phase::Merge)?;
+
+            // empty run is skipped
+            if let Some(wco) = reader.next_record().phase(Phase::Merge)? {
+                queue.push(MergePair(wco, reader));
+            }
+        }
+
+        Ok(RunMerger { queue })
+    }
+
+    /// next record of all runs, `None` once every run is read to its end
+    pub fn next_record(&mut self) -> Result<Option<WordCountOffset<K>>, Error> {
+        let MergePair(wco, mut reader) = match self.queue.pop() {
+            Some(pair) => pair,
+            None => return Ok(None),
+        };
+
+        // enqueue if temp file is not empty
+        if let Some(next) = reader.next_record().phase(Phase::Merge)? {
+            self.queue.push(MergePair(next, reader));
+        }
+
+        Ok(Some(wco))
+    }
+}
+
+/// merge a group of runs into one new run, combining counts of same word
+pub fn merge_group<K: WordKey>(runs: Vec<File>, planner: &MergePlanner, cancel: &CancelToken) -> Result<File, Error> {
+    // one more buffer for the writer
+    let buffer_size = planner.buffer_size(runs.len() + 1);
+
+    let tmp_file = tempfile::tempfile().phase(Phase::Merge)?;
+    let mut writer = BufWriter::with_capacity(buffer_size, tmp_file);
+    spill::begin(&mut writer).phase(Phase::Merge)?;
+    spill::charge(spill::HEADER_SIZE).phase(Phase::Merge)?;
+
+    let mut records = 0;
+    let mut last: Option<WordCountOffset<K>> = None;
+    let mut merger = RunMerger::<K>::new(runs, buffer_size)?;
+
+    while let Some(wco) = merger.next_record()? {
+        cancel.check().phase(Phase::Merge)?;
+
+        match &mut last {
+            Some(item) if item.0 == wco.0 => {
+                item.1 += wco.1;
+                item.2 = item.2.min(wco.2);
+            }
+            _ => {
+                if let Some(item) = last.replace(wco) {
+                    write_record(&mut writer, &item).phase(Phase::Merge)?;
+                    records += 1;
+                }
+            }
+        }
+    }
+
+    if let Some(item) = last {
+        write_record(&mut writer, &item).phase(Phase::Merge)?;
+        records += 1;
+    }
+
+    let mut file = writer.into_inner().phase(Phase::Merge)?;
+    spill::finish(&mut file, records).phase(Phase::Merge)?;
+
+    Ok(file)
+}
+
+/// merge all runs into `merger`.
+///
+/// at most `planner.fan_in()` runs are opened at the same time, if there are
+/// more runs, they are merged group by group into fewer runs first.
+/// `cancel` is checked between records, runs are dropped with the error.
+pub fn merge_runs<K: WordKey>(
+    mut runs: Vec<File>,
+    planner: &MergePlanner,
+    merger: &mut MergeCounter<K>,
+    cancel: &CancelToken,
+) -> Result<(), Error> {
+    let fan_in = planner.fan_in();
+
+    for _ in 1..planner.passes(runs.len()) {
+        let mut merged = Vec::with_capacity(runs.len() / fan_in + 1);
+
+        while !runs.is_empty() {
+            let group = runs.split_off(runs.len().saturating_sub(fan_in));
+
+            if group.len() == 1 {
+                merged.extend(group);
+            } else {
+                merged.push(merge_group::<K>(group, planner, cancel)?);
+            }
+        }
+
+        runs = merged;
+    }
+
+    let buffer_size = planner.buffer_size(runs.len());
+
+    let mut runs = RunMerger::new(runs, buffer_size)?;
+
+    while let Some(wco) = runs.next_record()? {
+        cancel.check().phase(Phase::Merge)?;
+
+        merger.count(wco.0, wco.1, wco.2);
+    }
+
+    Ok(())
+}
 
 /// MergeCounter works like reduce
-pub struct MergeCounter {
-    inner: Vec<WordCountOffset>,
+pub struct MergeCounter<K = String> {
+    inner: Vec<WordCountOffset<K>>,
 
-    pub ans: Option<(String, u64)>,
+    pub ans: Option<(K, Location)>,
 }
 
-impl MergeCounter {
+impl<K: WordKey> MergeCounter<K> {
     pub fn new() -> Result<Self, Error> {
         Ok(MergeCounter {
             inner: Vec::new(),
@@ -17,15 +175,15 @@ impl MergeCounter {
         })
     }
 
-    /// count with offset
-    pub fn count(&mut self, key: String, other_count: u64, offset: u64) {
+    /// count with location
+    pub fn count(&mut self, key: K, other_count: u64, location: Location) {
         let item = self.inner.last_mut();
 
         match item {
             Some(item) => {
                 if item.0 == key {
                     // 重复出现的单词, 合并计数.
-                    // 由于重复出现, offset 不需要再考虑了.
+                    // 由于重复出现, location 不需要再考虑了.
                     item.1 += other_count
                 } else {
                     // 最后一个元素和当前插入的单词不等的话, 那最后一个元素可以被删除
@@ -34,10 +192,10 @@ impl MergeCounter {
                     if last.1 == 1 {
                         // 如果只出现一次, 比较 offset
                         match &mut self.ans {
-                            Some((word, offset)) => {
-                                if last.2 < *offset {
+                            Some((word, location)) => {
+                                if last.2 < *location {
                                     *word = last.0;
-                                    *offset = last.2;
+                                    *location = last.2;
                                 }
                             }
 
@@ -49,35 +207,32 @@ impl MergeCounter {
                         // 出现多次的元素直接删除
                     }
 
-                    self.inner.push(WordCountOffset(key, other_count, offset))
+                    self.inner.push(WordCountOffset(key, other_count, location))
                 }
             }
-            None => self.inner.push(WordCountOffset(key, other_count, offset)),
+            None => self.inner.push(WordCountOffset(key, other_count, location)),
         }
     }
 
-    pub fn get_ans(&mut self) -> Option<(String, u64)> {
+    pub fn get_ans(&mut self) -> Option<(K, Location)> {
         // 合并完成后至多存在一个元素
         let last = self.inner.pop();
 
-        match last {
-            Some(wco) => {
-                if wco.1 == 1 {
-                    // 与 self.ans 作比较选择 offset 最小的
+        if let Some(wco) = last {
+            if wco.1 == 1 {
+                // 与 self.ans 作比较选择 offset 最小的
 
-                    match &mut self.ans {
-                        Some((word, offset)) => {
-                            if wco.2 < *offset {
-                                *word = wco.0;
-                                *offset = wco.2;
-                            }
+                match &mut self.ans {
+                    Some((word, location)) => {
+                        if wco.2 < *location {
+                            *word = wco.0;
+                            *location = wco.2;
                         }
-
-                        None => self.ans = Some((wco.0, wco.2)),
                     }
+
+                    None => self.ans = Some((wco.0, wco.2)),
                 }
             }
-            None => {}
         }
 
         self.ans.clone()
@@ -86,17 +241,64 @@ impl MergeCounter {
 
 #[cfg(test)]
 mod test {
-    use super::super::merge::MergeCounter;
+    use super::super::count::Counter;
+    use super::super::merge::{merge_runs, MergeCounter};
+    use super::super::io::Location;
+    use super::super::plan::{MergePlanner, MIN_READ_BUFFER};
+    use crate::cancel::CancelToken;
 
     #[test]
     fn test() {
-        let mut merger = MergeCounter::new().unwrap();
+        let mut merger: MergeCounter = MergeCounter::new().unwrap();
+
+        merger.count("a".into(), 1, Location::new(0, 1));
+        merger.count("b".into(), 1, Location::new(2, 2));
+        merger.count("c".into(), 1, Location::new(4, 3));
+        merger.count("a".into(), 1, Location::new(6, 4));
+
+        assert_eq!(merger.get_ans(), Some(("a".into(), Location::new(0, 1))));
+    }
+
+    #[test]
+    fn test_multi_pass() {
+        let mut runs = Vec::new();
+
+        // "w{i}" appears in run i and i + 1, except "w9" which only in run 9
+        for i in 0..10u64 {
+            let mut counter: Counter = Counter::new();
+
+            counter.count(format!("w{}", i), Location::new(i * 10, i * 2 + 1));
+            if i > 0 {
+                counter.count(format!("w{}", i - 1), Location::new(i * 10 + 5, i * 2 + 2));
+            }
+
+            runs.push(counter.flush().unwrap());
+        }
+
+        // fan-in 3 needs three passes for 10 runs
+        let planner = MergePlanner::new(MIN_READ_BUFFER * 4, 3);
+        assert_eq!(planner.passes(runs.len()), 3);
+
+        let mut merger: MergeCounter = MergeCounter::new().unwrap();
+        merge_runs(runs, &planner, &mut merger, &CancelToken::new()).unwrap();
+
+        assert_eq!(merger.get_ans(), Some(("w9".into(), Location::new(90, 19))));
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut counter: Counter = Counter::new();
+        counter.count("a".into(), Location::new(0, 1));
+
+        let runs = vec![counter.flush().unwrap()];
+
+        let cancel = CancelToken::new();
+        cancel.cancel();
 
-        merger.count("a".into(), 1, 0);
-        merger.count("b".into(), 1, 2);
-        merger.count("c".into(), 1, 4);
-        merger.count("a".into(), 1, 6);
+        let mut merger: MergeCounter = MergeCounter::new().unwrap();
+        let err = merge_runs(runs, &MergePlanner::default(), &mut merger, &cancel).unwrap_err();
 
-        assert_eq!(merger.get_ans(), Some(("a".into(), 0)));
+        assert!(err.is_cancelled());
+        assert_eq!(merger.get_ans(), None);
     }
 }
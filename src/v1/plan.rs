@@ -0,0 +1,103 @@
+/// default memory budget shared by all run readers during merge
+pub const DEFAULT_MERGE_MEMORY: u64 = 256 * 1024 * 1024;
+
+/// default upper bound of runs opened at the same time
+pub const DEFAULT_MAX_FAN_IN: usize = 512;
+
+/// a read buffer smaller than this turns the merge into random IO
+pub const MIN_READ_BUFFER: u64 = 64 * 1024;
+
+/// MergePlanner decides how many runs are merged at once and how large
+/// each run reader is.
+///
+/// memory budget is split evenly across opened runs, so fan-in is capped
+/// both by `max_fan_in` (fd limit) and by `memory / MIN_READ_BUFFER`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MergePlanner {
+    memory: u64,
+    max_fan_in: usize,
+}
+
+impl MergePlanner {
+    pub fn new(memory: u64, max_fan_in: usize) -> Self {
+        MergePlanner { memory, max_fan_in }
+    }
+
+    /// max runs merged in one pass, at least 2 so every pass makes progress
+    ///
+    /// one buffer is left for the writer of an intermediate pass.
+    pub fn fan_in(&self) -> usize {
+        let by_memory = (self.memory / MIN_READ_BUFFER).saturating_sub(1) as usize;
+
+        by_memory.min(self.max_fan_in).max(2)
+    }
+
+    /// size of each buffer when the budget is split into `buffers`, readers
+    /// and the writer if there's one
+    pub fn buffer_size(&self, buffers: usize) -> usize {
+        let buffers = buffers.max(1) as u64;
+
+        (self.memory / buffers).max(MIN_READ_BUFFER) as usize
+    }
+
+    /// number of passes over the data to merge `runs` into one
+    pub fn passes(&self, runs: usize) -> usize {
+        let fan_in = self.fan_in();
+
+        let mut runs = runs;
+        let mut passes = 1;
+
+        while runs > fan_in {
+            runs = runs.div_ceil(fan_in);
+            passes += 1;
+        }
+
+        passes
+    }
+}
+
+impl Default for MergePlanner {
+    fn default() -> Self {
+        MergePlanner::new(DEFAULT_MERGE_MEMORY, DEFAULT_MAX_FAN_IN)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MergePlanner, MIN_READ_BUFFER};
+
+    #[test]
+    fn test_fan_in() {
+        let planner = MergePlanner::new(MIN_READ_BUFFER * 8, 100);
+        assert_eq!(planner.fan_in(), 7);
+
+        let planner = MergePlanner::new(MIN_READ_BUFFER * 1000, 100);
+        assert_eq!(planner.fan_in(), 100);
+
+        let planner = MergePlanner::new(0, 100);
+        assert_eq!(planner.fan_in(), 2);
+    }
+
+    #[test]
+    fn test_buffer_size() {
+        let planner = MergePlanner::new(MIN_READ_BUFFER * 8, 100);
+
+        assert_eq!(planner.buffer_size(2), (MIN_READ_BUFFER * 4) as usize);
+        assert_eq!(planner.buffer_size(1000), MIN_READ_BUFFER as usize);
+
+        // a full intermediate group and its writer stay within the budget
+        let buffers = planner.fan_in() + 1;
+        assert!((planner.buffer_size(buffers) * buffers) as u64 <= MIN_READ_BUFFER * 8);
+    }
+
+    #[test]
+    fn test_passes() {
+        let planner = MergePlanner::new(MIN_READ_BUFFER * 1000, 4);
+
+        assert_eq!(planner.passes(1), 1);
+        assert_eq!(planner.passes(4), 1);
+        assert_eq!(planner.passes(5), 2);
+        assert_eq!(planner.passes(16), 2);
+        assert_eq!(planner.passes(17), 3);
+    }
+}
@@ -1,3 +1,6 @@
+pub mod checkpoint;
 pub mod count;
+pub mod fanout;
 pub mod io;
+pub mod manifest;
 pub mod utils;
\ No newline at end of file
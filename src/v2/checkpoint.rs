@@ -0,0 +1,286 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use super::manifest::{Partition, PartitionStats};
+use crate::error::{Error, ErrorKind, Phase, ResultExt};
+use crate::spill;
+use crate::v1::io::Location;
+
+/// source bytes read between two checkpoints of a split
+pub const DEFAULT_CHECKPOINT_INTERVAL: u64 = 1024 * 1024 * 1024;
+
+const CHECKPOINT_FILE: &str = "checkpoint";
+const PARTITION_PREFIX: &str = "part-";
+
+/// where the pipeline was when a checkpoint is saved
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Stage<K> {
+    /// splitting the source, `next` is the first word not written yet
+    Scan { next: Location },
+    /// source is split, partitions over the threshold are being split again
+    Resplit,
+    /// counting partitions, with unique words found so far
+    Count { ans: Vec<(K, Location)> },
+}
+
+/// stage of a loaded checkpoint, with its partitions reopened
+pub type Resumed<K> = (Stage<K>, Vec<Partition>);
+
+/// a partition file and what's written into it before the checkpoint
+#[derive(Serialize, Deserialize, Debug)]
+struct PartitionState {
+    name: String,
+    len: u64,
+    stats: PartitionStats,
+    hot: bool,
+    level: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Checkpoint<K> {
+    /// input and options the checkpoint was written with
+    settings: String,
+    stage: Stage<K>,
+    partitions: Vec<PartitionState>,
+}
+
+/// StateDir holds named partition files and the last checkpoint.
+///
+/// a checkpoint is written to a temp file and renamed, so a crash leaves
+/// either the previous one or the new one.
+#[derive(Clone)]
+pub struct StateDir {
+    dir: PathBuf,
+    settings: String,
+    interval: u64,
+
+    next_id: u64,
+}
+
+impl StateDir {
+    /// `settings` must be equal on resume, checkpoint of another input is refused
+    pub fn open<P: AsRef<Path>>(dir: P, settings: String, interval: u64) -> Result<Self, Error> {
+        let dir = dir.as_ref().to_owned();
+        fs::create_dir_all(&dir).file(&dir).phase(Phase::Checkpoint)?;
+
+        Ok(StateDir {
+            dir,
+            settings,
+            interval: interval.max(1),
+            next_id: 0,
+        })
+    }
+
+    /// source bytes between two checkpoints of a split
+    pub fn interval(&self) -> u64 {
+        self.interval
+    }
+
+    /// new named partition file in this directory
+    pub fn create_partition(&mut self) -> Result<Partition, Error> {
+        let path = self.dir.join(format!("{}{}", PARTITION_PREFIX, self.next_id));
+        self.next_id += 1;
+
+        Partition::create_at(&path).file(&path)
+    }
+
+    /// sync partitions to disk, then record them with `stage`
+    pub fn save<'a, K, I>(&self, stage: Stage<K>, partitions: I) -> Result<(), Error>
+    where
+        K: Serialize,
+        I: IntoIterator<Item = &'a Partition>,
+    {
+        let mut states = Vec::new();
+
+        for it in partitions {
+            // every partition of a state directory is named
+            let path = match &it.path {
+                Some(path) => path,
+                None => continue,
+            };
+
+            // a closed one is synced when it's closed
+            if let Some(file) = it.file() {
+                file.sync_data().file(path).phase(Phase::Checkpoint)?;
+            }
+
+            states.push(PartitionState {
+                name: path.file_name().unwrap().to_string_lossy().into_owned(),
+                len: it.len().file(path).phase(Phase::Checkpoint)?,
+                stats: it.stats.clone(),
+                hot: it.hot,
+                level: it.level,
+            });
+        }
+
+        let checkpoint = Checkpoint {
+            settings: self.settings.clone(),
+            stage,
+            partitions: states,
+        };
+
+        let tmp = self.dir.join(format!("{}.tmp", CHECKPOINT_FILE));
+        let path = self.dir.join(CHECKPOINT_FILE);
+
+        let mut writer = BufWriter::new(File::create(&tmp).file(&tmp).phase(Phase::Checkpoint)?);
+        bincode::serialize_into(&mut writer, &checkpoint).file(&tmp).phase(Phase::Checkpoint)?;
+
+        let file = writer.into_inner().file(&tmp).phase(Phase::Checkpoint)?;
+        file.sync_all().file(&tmp).phase(Phase::Checkpoint)?;
+
+        fs::rename(&tmp, &path).file(&path).phase(Phase::Checkpoint)
+    }
+
+    /// settings the last checkpoint in `dir` was written with, `None` if there is none
+    pub fn saved_settings<P: AsRef<Path>>(dir: P) -> Result<Option<String>, Error> {
+        let path = dir.as_ref().join(CHECKPOINT_FILE);
+
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(Error::from(err).with_file(path).with_phase(Phase::Checkpoint)),
+        };
+
+        // settings is the first field of a checkpoint, whatever its key type
+        let settings: String = bincode::deserialize_from(BufReader::new(file))
+            .file(&path)
+            .phase(Phase::Checkpoint)?;
+
+        Ok(Some(settings))
+    }
+
+    /// last checkpoint with its partitions reopened, `None` if there is none
+    ///
+    /// anything written after the checkpoint is cut off. partitions of a scan
+    /// are positioned at their end for appending, others at the start.
+    pub fn load<K: DeserializeOwned>(&mut self) -> Result<Option<Resumed<K>>, Error> {
+        let path = self.dir.join(CHECKPOINT_FILE);
+
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(Error::from(err).with_file(path).with_phase(Phase::Checkpoint)),
+        };
+
+        let checkpoint: Checkpoint<K> = bincode::deserialize_from(BufReader::new(file))
+            .file(&path)
+            .phase(Phase::Checkpoint)?;
+
+        if checkpoint.settings != self.settings {
+            let msg = format!("checkpoint in {} is for another input or options", self.dir.display());
+
+            return Err(Error::new(ErrorKind::InvalidArgument(msg)).with_phase(Phase::Checkpoint));
+        }
+
+        let append = matches!(checkpoint.stage, Stage::Scan { .. });
+        let mut partitions = Vec::with_capacity(checkpoint.partitions.len());
+
+        for state in checkpoint.partitions {
+            let path = self.dir.join(&state.name);
+            let file = reopen(&path, state.len, append).file(&path).phase(Phase::Checkpoint)?;
+            spill::charge(state.len).phase(Phase::Checkpoint)?;
+
+            // only a scan writes into them again, others are read one by one
+            let file = if append { Some(file) } else { None };
+
+            if let Some(id) = state.name.strip_prefix(PARTITION_PREFIX).and_then(|id| id.parse::<u64>().ok()) {
+                self.next_id = self.next_id.max(id + 1);
+            }
+
+            partitions.push(Partition::restore(file, path, state.stats, state.hot, state.level));
+        }
+
+        Ok(Some((checkpoint.stage, partitions)))
+    }
+
+    /// forget a previous run, its partition files would be overwritten
+    pub fn reset(&mut self) -> Result<(), Error> {
+        for entry in fs::read_dir(&self.dir).file(&self.dir).phase(Phase::Checkpoint)? {
+            let path = entry.file(&self.dir).phase(Phase::Checkpoint)?.path();
+
+            let stale = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) => name == CHECKPOINT_FILE || name.starts_with(PARTITION_PREFIX),
+                None => false,
+            };
+
+            if stale {
+                fs::remove_file(&path).file(&path).phase(Phase::Checkpoint)?;
+            }
+        }
+
+        self.next_id = 0;
+
+        Ok(())
+    }
+
+    /// remove the checkpoint once the answer is found
+    pub fn clear(&self) -> Result<(), Error> {
+        let path = self.dir.join(CHECKPOINT_FILE);
+
+        fs::remove_file(&path).file(&path).phase(Phase::Checkpoint)
+    }
+}
+
+fn reopen(path: &Path, len: u64, append: bool) -> std::io::Result<File> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+
+    file.set_len(len)?;
+
+    if append {
+        file.seek(SeekFrom::End(0))?;
+    }
+
+    Ok(file)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Stage, StateDir};
+    use crate::v1::io::Location;
+    use crate::v2::utils::hash;
+
+    #[test]
+    fn test_save_load() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut state = StateDir::open(dir.path(), "input".into(), 1).unwrap();
+        let mut partition = state.create_partition().unwrap();
+
+        bincode::serialize_into(partition.writer().unwrap(), &("qwer".to_string(), 0u64)).unwrap();
+        partition.stats.add(hash(b"qwer"), 0, 12);
+
+        let stage: Stage<String> = Stage::Scan { next: Location::new(5, 2) };
+        state.save(stage, std::iter::once(&partition)).unwrap();
+
+        // unsaved record is cut off on load
+        bincode::serialize_into(partition.writer().unwrap(), &("zxcv".to_string(), 5u64)).unwrap();
+
+        assert_eq!(StateDir::saved_settings(dir.path()).unwrap(), Some("input".into()));
+        assert_eq!(StateDir::saved_settings(dir.path().join("missing")).unwrap(), None);
+
+        let mut other = StateDir::open(dir.path(), "other input".into(), 1).unwrap();
+        assert!(other.load::<String>().is_err());
+
+        let mut state = StateDir::open(dir.path(), "input".into(), 1).unwrap();
+        let (stage, partitions) = state.load::<String>().unwrap().unwrap();
+
+        match stage {
+            Stage::Scan { next } => assert_eq!(next, Location::new(5, 2)),
+            _ => panic!("wrong stage"),
+        }
+
+        assert_eq!(partitions.len(), 1);
+        assert_eq!(partitions[0].stats.records, 1);
+        assert_eq!(partitions[0].len().unwrap(), 12 + 20);
+
+        // ids of loaded partitions aren't reused
+        assert_eq!(state.next_id, 1);
+
+        state.clear().unwrap();
+        assert!(state.load::<String>().unwrap().is_none());
+    }
+}
@@ -1,57 +1,90 @@
-use std::fs::File;
-
-use failure::Error;
+use crate::cancel::CancelToken;
+use crate::error::{Error, ErrorKind, Phase, ResultExt};
+use std::cmp::Reverse;
 use std::collections::HashMap;
-use std::io::{Read, BufReader, BufRead};
-use crate::v2::io::WordOffset;
-use bincode::ErrorKind;
+use crate::v2::io::{WordOffset, HOT_KEYS};
+use crate::v2::checkpoint::{Stage, StateDir};
+use crate::v2::manifest::{remove_file, Manifest, Partition};
+use crate::key::WordKey;
+use crate::v1::io::Location;
+use crate::spill::SpillReader;
+
+/// read buffer of a partition file
+const READ_BUFFER_SIZE: usize = 8 * 1024 * 1024;
+
+pub struct Counter<K = String> {
+    /// partitions ordered by min offset descending
+    chunks: Vec<Partition>,
+    map: HashMap<K, (u64, Location)>,
 
-pub struct Counter {
-    chunks: Vec<File>,
-    map: HashMap<String, (u64, u64)>,
+    ans: Vec<(K, Location)>,
 
-    ans: Vec<(String, u64)>,
+    state: Option<StateDir>,
+    cancel: CancelToken,
 }
 
-impl Counter {
-    pub fn new(chunks: Vec<File>) -> Result<Self, Error> {
+impl<K: WordKey> Counter<K> {
+    pub fn new(manifest: Manifest) -> Result<Self, Error> {
+        let mut chunks: Vec<Partition> = manifest.partitions.into_iter()
+            .filter(|it| !it.stats.is_empty())
+            .collect();
+
+        // records are appended in source order, so min offset is the first record
+        chunks.sort_by_key(|it| Reverse(it.stats.min_offset));
+
         Ok(Counter {
             chunks,
             map: HashMap::new(),
             ans: Vec::new(),
+            state: None,
+            cancel: CancelToken::new(),
         })
     }
 
-    pub fn count(&mut self, word: String, offset: u64) {
-        let mut item = self.map.get_mut(&word);
+    /// checkpoint into `state` after every partition, `ans` are the unique
+    /// words found before the checkpoint this counter resumes from
+    pub fn with_state(mut self, state: StateDir, ans: Vec<(K, Location)>) -> Self {
+        self.state = Some(state);
+        self.ans = ans;
+        self
+    }
+
+    /// stop before the next partition once `cancel` is cancelled
+    pub fn with_cancel(mut self, cancel: CancelToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    pub fn count(&mut self, word: K, location: Location) {
+        let item = self.map.get_mut(&word);
 
         match item {
-            Some((count, offset)) => {
+            Some((count, _location)) => {
                 *count += 1
             }
             None => {
-                self.map.insert(word, (1, offset));
+                self.map.insert(word, (1, location));
             }
         }
     }
 
     pub fn rotate(&mut self) {
-        let mut ans: Option<(String, u64)> = None;
+        let mut ans: Option<(K, Location)> = None;
 
-        for (word, (count, offset)) in self.map.iter() {
+        for (word, (count, location)) in self.map.iter() {
             if *count != 1 {
                 continue;
             }
 
             match &mut ans {
-                Some((ans_word, ans_offset)) => {
-                    if offset < ans_offset {
+                Some((ans_word, ans_location)) => {
+                    if location < ans_location {
                         *ans_word = word.clone();
-                        *ans_offset = *offset;
+                        *ans_location = *location;
                     }
                 }
                 None => {
-                    ans = Some((word.clone(), *offset));
+                    ans = Some((word.clone(), *location));
                 }
             }
         }
@@ -63,35 +96,227 @@ impl Counter {
         self.map.clear();
     }
 
-    pub fn run(&mut self) {
-        while let Some(mut chunk) = self.chunks.pop() {
-            let mut buff = Vec::new();
-            chunk.read_to_end(&mut buff);
+    /// count one partition, records are deserialized straight from a buffered reader
+    fn count_chunk(&mut self, chunk: Partition) -> Result<(), Error> {
+        // a little headroom over the sketch error avoids rehashing
+        let distinct = chunk.stats.distinct();
+        let capacity = (distinct + distinct / 8).min(chunk.stats.records);
 
-            let mut reader = BufReader::new(&*buff);
+        self.map = HashMap::with_capacity(capacity as usize);
 
-            loop {
-                let wo: Result<WordOffset, Box<ErrorKind>> = bincode::deserialize_from(&mut reader);
+        let file = chunk.into_file().phase(Phase::Count)?;
+        let mut reader = SpillReader::new(file, READ_BUFFER_SIZE).phase(Phase::Count)?;
+
+        while let Some(wo) = reader.next_record().phase(Phase::Count)? {
+            let wo: WordOffset<K> = wo;
+
+            self.count(wo.0, wo.1);
+        }
+
+        Ok(())
+    }
+
+    /// count a hot partition, it has only a few distinct words so a short
+    /// list is scanned per record instead of building a map
+    ///
+    /// the list holds at most `HOT_KEYS` words, the distinct count is only
+    /// an estimate, beyond that the rest is counted by the map.
+    fn aggregate_chunk(&mut self, chunk: Partition) -> Result<(), Error> {
+        let mut words: Vec<(K, u64, Location)> = Vec::new();
+
+        let file = chunk.into_file().phase(Phase::Count)?;
+        let mut reader = SpillReader::new(file, READ_BUFFER_SIZE).phase(Phase::Count)?;
+
+        while let Some(wo) = reader.next_record().phase(Phase::Count)? {
+            let wo: WordOffset<K> = wo;
+
+            match words.iter().position(|it| it.0 == wo.0) {
+                Some(idx) => words[idx].1 += 1,
+                None if words.len() < HOT_KEYS as usize => words.push((wo.0, 1, wo.1)),
+                None => {
+                    self.map = words.into_iter().map(|(word, count, location)| (word, (count, location))).collect();
+                    self.count(wo.0, wo.1);
+
+                    while let Some(wo) = reader.next_record().phase(Phase::Count)? {
+                        let wo: WordOffset<K> = wo;
 
-                match wo {
-                    Ok(wo) => {
                         self.count(wo.0, wo.1);
                     }
-                    Err(_) => {
-                        break;
-                    }
+
+                    self.rotate();
+
+                    return Ok(());
+                }
+            }
+        }
+
+        let unique = words.into_iter().filter(|it| it.1 == 1).min_by_key(|it| it.2);
+
+        if let Some((word, _, location)) = unique {
+            self.ans.push((word, location));
+        }
+
+        Ok(())
+    }
+
+    /// location of the earliest unique word found so far
+    pub fn best_location(&self) -> Option<Location> {
+        self.ans.iter().map(|it| it.1).min()
+    }
+
+    /// partitions not counted yet, for partial statistics after a cancel
+    pub fn remaining(self) -> Manifest {
+        Manifest::new(self.chunks)
+    }
+
+    /// count partitions in ascending min offset order
+    ///
+    /// once a partition starts after the best candidate, neither it nor the
+    /// remaining partitions can hold an earlier unique word.
+    pub fn run(&mut self) -> Result<(), Error> {
+        while let Some(chunk) = self.chunks.pop() {
+            if self.cancel.is_cancelled() {
+                // the last checkpoint has this partition left already
+                self.chunks.push(chunk);
+
+                return Err(Error::new(ErrorKind::Cancelled).with_phase(Phase::Count));
+            }
+
+            if let Some(best) = self.best_location() {
+                if chunk.stats.min_offset > best.offset {
+                    self.chunks.push(chunk);
+                    break;
                 }
             }
 
-            self.rotate();
+            let path = chunk.path.clone();
+
+            if chunk.hot {
+                self.aggregate_chunk(chunk)?;
+            } else {
+                self.count_chunk(chunk)?;
+
+                self.rotate();
+            }
+
+            if let Some(state) = &self.state {
+                let stage = Stage::Count { ans: self.ans.clone() };
+                state.save(stage, self.chunks.iter())?;
+            }
+
+            if let Some(path) = path {
+                remove_file(path).phase(Phase::Count)?;
+            }
         }
+
+        // pruned
+        for chunk in self.chunks.drain(..) {
+            chunk.remove().phase(Phase::Count)?;
+        }
+
+        if let Some(state) = &self.state {
+            state.clear()?;
+        }
+
+        Ok(())
     }
 
-    pub fn finish(mut self) -> Option<(String, u64)> {
+    pub fn finish(mut self) -> Option<(K, Location)> {
         self.ans.sort_by(|lhs, rhs| {
             rhs.1.cmp(&lhs.1)
         });
 
         self.ans.pop()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::Counter;
+    use crate::cancel::CancelToken;
+    use crate::v1::io::Location;
+    use crate::v2::io::{WordOffset, HOT_KEYS};
+    use crate::v2::manifest::{Manifest, Partition};
+    use crate::v2::utils::hash;
+
+    fn partition(records: &[(&str, u64)]) -> Partition {
+        let mut partition = Partition::create().unwrap();
+
+        // words are five bytes apart
+        for (word, offset) in records {
+            let wo = WordOffset(word.to_string(), Location::new(*offset, offset / 5 + 1));
+            let size = bincode::serialized_size(&wo).unwrap();
+
+            bincode::serialize_into(partition.writer().unwrap(), &wo).unwrap();
+            partition.stats.add(hash(wo.0.as_bytes()), *offset, size);
+        }
+        partition.finish().unwrap();
+
+        partition
+    }
+
+    #[test]
+    fn test_run() {
+        let chunk = partition(&[("qwer", 0), ("abcd", 5), ("qwer", 10), ("zxcv", 15)]);
+
+        let mut counter: Counter = Counter::new(Manifest::new(vec![chunk])).unwrap();
+        counter.run().unwrap();
+
+        assert_eq!(counter.finish(), Some(("abcd".into(), Location::new(5, 2))));
+    }
+
+    #[test]
+    fn test_hot_overflow() {
+        let words: Vec<String> = (0..HOT_KEYS * 2).map(|idx| format!("w{:03}", idx)).collect();
+        let twice = words.iter().chain(words.iter()).map(|it| it.as_str());
+
+        // every word twice but the last one, more distinct words than a hot list holds
+        let mut records: Vec<(&str, u64)> = twice.zip((0..).step_by(5)).collect();
+        records.pop();
+
+        let mut chunk = partition(&records);
+        chunk.hot = true;
+
+        let mut counter: Counter = Counter::new(Manifest::new(vec![chunk])).unwrap();
+        counter.run().unwrap();
+
+        let (word, location) = counter.finish().unwrap();
+        assert_eq!(word, words[words.len() - 1]);
+        assert_eq!(location.offset, (HOT_KEYS * 2 - 1) * 5);
+    }
+
+    #[test]
+    fn test_prune() {
+        let late = partition(&[("zxcv", 20), ("zxcv", 25)]);
+        let empty = partition(&[]);
+        let early = partition(&[("qwer", 0), ("abcd", 5), ("qwer", 10)]);
+        let middle = partition(&[("asdf", 15)]);
+
+        let manifest = Manifest::new(vec![late, empty, early, middle]);
+
+        let mut counter: Counter = Counter::new(manifest).unwrap();
+        assert_eq!(counter.chunks.len(), 3);
+
+        counter.run().unwrap();
+        assert!(counter.chunks.is_empty());
+
+        assert_eq!(counter.finish(), Some(("abcd".into(), Location::new(5, 2))));
+    }
+
+    #[test]
+    fn test_cancel() {
+        let early = partition(&[("qwer", 0), ("abcd", 5)]);
+        let late = partition(&[("zxcv", 10)]);
+
+        let cancel = CancelToken::new();
+        cancel.cancel();
+
+        let mut counter: Counter = Counter::new(Manifest::new(vec![early, late])).unwrap().with_cancel(cancel);
+
+        assert!(counter.run().unwrap_err().is_cancelled());
+        assert_eq!(counter.best_location(), None);
+
+        // nothing is counted, both partitions are left
+        assert_eq!(counter.remaining().records(), 3);
+    }
+}
@@ -0,0 +1,152 @@
+use std::io;
+
+/// default memory limit of the whole process
+pub const DEFAULT_MEMORY: u64 = 16 * 1024 * 1024 * 1024;
+
+/// in-memory counting map takes several times the partition size on disk,
+/// a 16 GiB budget gives the original 2 GiB partition threshold
+const MEMORY_PER_PARTITION_BYTE: u64 = 8;
+
+/// spill record of a word is about twice the source bytes for short words,
+/// since every record carries a length prefix and an offset
+const SPILL_FACTOR: u64 = 2;
+
+/// hashing doesn't split evenly, plan partitions a bit smaller than the threshold
+const HEADROOM_PERCENT: u64 = 125;
+
+/// fds kept for stdio, the source file and the counter
+const RESERVED_FDS: u64 = 16;
+
+/// query soft limit of open files, raising it towards the hard limit when
+/// `wanted` is larger
+pub fn fd_limit(wanted: u64) -> io::Result<u64> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let soft = limit.rlim_cur;
+
+    if wanted <= soft {
+        return Ok(soft);
+    }
+
+    let hard = if limit.rlim_max == libc::RLIM_INFINITY {
+        wanted
+    } else {
+        limit.rlim_max.min(wanted)
+    };
+
+    if hard <= soft {
+        return Ok(soft);
+    }
+
+    limit.rlim_cur = hard;
+
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } != 0 {
+        // not allowed to raise, keep the current one
+        return Ok(soft);
+    }
+
+    Ok(hard)
+}
+
+/// FanOut chooses how many partitions a split writes
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FanOut {
+    memory: u64,
+    max_files: u64,
+}
+
+impl FanOut {
+    pub fn new(memory: u64, max_files: u64) -> Self {
+        FanOut {
+            memory,
+            max_files: max_files.max(1),
+        }
+    }
+
+    /// fan-out bounded by the process fd limit, raised if allowed
+    pub fn detect(memory: u64, input_size: u64) -> io::Result<Self> {
+        let planned = FanOut::new(memory, u64::MAX).initial(input_size);
+        let limit = fd_limit(planned.saturating_add(RESERVED_FDS))?;
+
+        Ok(FanOut::new(memory, limit.saturating_sub(RESERVED_FDS)))
+    }
+
+    /// largest partition expected to be counted in memory
+    pub fn threshold(&self) -> u64 {
+        (self.memory / MEMORY_PER_PARTITION_BYTE).max(1)
+    }
+
+    /// partitions to split `bytes` of spill records into, so that each of
+    /// them fits in memory with high probability
+    pub fn partitions(&self, bytes: u64) -> u64 {
+        let target = self.threshold() * 100 / HEADROOM_PERCENT;
+
+        bytes.div_ceil(target.max(1)).clamp(1, self.max_files)
+    }
+
+    /// parts to split a partition of `bytes` into again while `open` other
+    /// partition files are open, the partition read from holds one too.
+    ///
+    /// `None` when there aren't files left for 2 parts.
+    pub fn resplit(&self, bytes: u64, open: u64) -> Option<u64> {
+        let free = self.max_files.saturating_sub(open + 1);
+
+        if free < 2 {
+            return None;
+        }
+
+        Some(self.partitions(bytes).clamp(2, free))
+    }
+
+    /// partitions for the first split of a source file of `input_size`
+    pub fn initial(&self, input_size: u64) -> u64 {
+        self.partitions(input_size.saturating_mul(SPILL_FACTOR))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{fd_limit, FanOut};
+
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    #[test]
+    fn test_partitions() {
+        let fanout = FanOut::new(16 * GIB, 1000);
+        assert_eq!(fanout.threshold(), 2 * GIB);
+
+        assert_eq!(fanout.initial(10 * 1024 * 1024), 1);
+        assert_eq!(fanout.initial(100 * GIB), 126);
+
+        // capped by open files
+        let fanout = FanOut::new(16 * GIB, 64);
+        assert_eq!(fanout.initial(100 * GIB), 64);
+        assert_eq!(fanout.resplit(100 * GIB, 0), Some(63));
+        assert_eq!(fanout.resplit(100 * GIB, 40), Some(23));
+        assert_eq!(fanout.resplit(1, 0), Some(2));
+        assert_eq!(fanout.resplit(100 * GIB, 62), None);
+    }
+
+    #[test]
+    fn test_detect() {
+        // sparse files can claim any size
+        let fanout = FanOut::detect(1, u64::MAX).unwrap();
+        assert!(fanout.initial(u64::MAX) >= 1);
+    }
+
+    #[test]
+    fn test_fd_limit() {
+        let soft = fd_limit(0).unwrap();
+        assert!(soft > 0);
+
+        // never lowered
+        assert!(fd_limit(soft + 1).unwrap() >= soft);
+    }
+}
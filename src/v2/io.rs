@@ -1,110 +1,252 @@
-use std::fs::File;
-use std::io::{Read, BufReader, BufRead, Write, Seek, SeekFrom};
-
-use super::utils::hash;
-use failure::Error;
-use std::process::id;
-use crate::v1::io::{ChunkFile, ChunkError, DEFAULT_CHUNK_SIZE};
+use std::marker::PhantomData;
+
+use super::fanout::FanOut;
+use super::checkpoint::{Stage, StateDir};
+use super::manifest::{remove_file, Manifest, Partition};
+use super::utils::{hash, hash_with_seed};
+use crate::cancel::CancelToken;
+use crate::error::{Error, ErrorKind, Phase, ResultExt};
+use crate::v1::io::{ChunkError, Location, ReadOptions};
 use std::path::Path;
 use serde::{Serialize, Deserialize};
-use bincode::ErrorKind;
+use crate::key::WordKey;
+use crate::token::TokenReader;
+use crate::spill::{self, SpillReader};
+
+/// read buffer of a partition split again
+const READ_BUFFER_SIZE: usize = 8 * 1024 * 1024;
 
-const CHUNK_COUNT: u64 = 50;
-const CHUNK_THRESHOLD: u64 = 2 * 1024 * 1024 * 1024;
+/// a big partition with at most this many distinct words isn't resplit,
+/// hashing can't separate copies of the same word
+pub const HOT_KEYS: u64 = 64;
 
 #[derive(Serialize, Deserialize, Debug)]
-pub struct WordOffset(pub String, pub u64);
+pub struct WordOffset<K = String>(pub K, pub Location);
+
+/// append a record to partition and update its statistics
+fn write_record<K: WordKey>(partition: &mut Partition, hash: u64, wo: &WordOffset<K>) -> Result<(), Error> {
+    let size = bincode::serialized_size(wo).phase(Phase::Split)?;
 
-pub struct HashSplitFile {
-    inner: ChunkFile,
-    chunks: Vec<File>,
+    spill::charge(size).phase(Phase::Split)?;
+    bincode::serialize_into(partition.writer().phase(Phase::Split)?, wo).phase(Phase::Split)?;
+    partition.stats.add(hash, wo.1.offset, size);
 
-    big_chunks: Vec<File>,
+    Ok(())
 }
 
-impl HashSplitFile
+pub struct HashSplitFile<K = String> {
+    inner: TokenReader,
+    fanout: FanOut,
+    /// partitions of the first split, created when the scan starts
+    initial: u64,
+    chunks: Vec<Partition>,
+
+    big_chunks: Vec<Partition>,
+
+    /// source is read to its end
+    scanned: bool,
+    state: Option<StateDir>,
+    next_checkpoint: u64,
+    cancel: CancelToken,
+
+    key: PhantomData<K>,
+}
+
+impl<K: WordKey> HashSplitFile<K>
 {
-    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
-        let chunk_file = ChunkFile::new(path, DEFAULT_CHUNK_SIZE)?;
-        let mut chunks = Vec::with_capacity(CHUNK_COUNT as usize);
+    pub fn new<P: AsRef<Path>>(path: P, options: &ReadOptions, fanout: FanOut) -> Result<Self, Error> {
+        let input_size = std::fs::metadata(path.as_ref()).file(path.as_ref()).phase(Phase::Read)?.len();
 
-        for _ in 0..CHUNK_COUNT {
-            let chunk = tempfile::tempfile()?;
-            chunks.push(chunk);
-        }
+        let chunk_file = TokenReader::open(path, options)?;
 
         Ok(HashSplitFile {
             inner: chunk_file,
-            chunks,
+            fanout,
+            initial: fanout.initial(input_size),
+            chunks: Vec::new(),
             big_chunks: Vec::new(),
+            scanned: false,
+            state: None,
+            next_checkpoint: 0,
+            cancel: options.cancel.clone(),
+            key: PhantomData,
         })
     }
 
-    fn split_big_chunks(&mut self) {
-        let mut chunks = Vec::new();
+    /// name partition files in `state`, and save a checkpoint there every
+    /// `state.interval()` source bytes
+    pub fn with_state(mut self, state: StateDir) -> Self {
+        self.next_checkpoint = state.interval();
+        self.state = Some(state);
+        self
+    }
+
+    /// continue from a checkpoint loaded from the state directory
+    pub fn resume(&mut self, stage: Stage<K>, partitions: Vec<Partition>) -> Result<(), Error> {
+        match stage {
+            Stage::Scan { next } => {
+                self.inner.seek(next)?;
+                self.next_checkpoint = next.offset + self.state.as_ref().map_or(0, StateDir::interval);
+            }
+            // partitions are split already
+            Stage::Resplit | Stage::Count { .. } => self.scanned = true,
+        }
+
+        self.chunks = partitions;
+
+        Ok(())
+    }
 
-        while let Some(mut file) = self.big_chunks.pop() {
-            let metadata = file.metadata().unwrap();
-            let count = metadata.len() / CHUNK_THRESHOLD + 1;
+    fn create_partition(&mut self) -> Result<Partition, Error> {
+        match &mut self.state {
+            Some(state) => state.create_partition(),
+            None => Partition::create(),
+        }
+        .phase(Phase::Split)
+    }
+
+    /// checkpoint all partitions, if there is a state directory
+    fn save(&self, stage: Stage<K>) -> Result<(), Error> {
+        match &self.state {
+            Some(state) => state.save(stage, self.chunks.iter().chain(self.big_chunks.iter())),
+            None => Ok(()),
+        }
+    }
+
+    /// parts a partition can be split into with the files left, anonymous
+    /// partitions keep their files open until they're counted
+    fn resplit_count(&self, partition: &Partition) -> Option<u64> {
+        let open = self.chunks.iter().chain(self.big_chunks.iter()).filter(|it| it.file().is_some()).count();
+
+        self.fanout.resplit(partition.stats.bytes, open as u64)
+    }
 
-            let mut part_chunks = Vec::with_capacity(count as usize);
+    /// split one partition again into `count` parts by hash with `level + 1`
+    /// as seed, empty parts are dropped and named ones closed
+    fn resplit(&mut self, partition: Partition, count: u64) -> Result<Vec<Partition>, Error> {
+        let level = partition.level + 1;
+
+        let mut part_chunks = Vec::with_capacity(count as usize);
+
+        for _ in 0..count {
+            let mut part = self.create_partition()?;
+            part.level = level;
+
+            part_chunks.push(part);
+        }
+
+        let file = partition.into_file().phase(Phase::Split)?;
+        let mut reader = SpillReader::new(file, READ_BUFFER_SIZE).phase(Phase::Split)?;
+
+        while let Some(wo) = reader.next_record().phase(Phase::Split)? {
+            let wo: WordOffset<K> = wo;
+            let h = hash_with_seed(&wo.0.key_bytes(), level as u64);
+            let idx = h % count;
+
+            write_record(&mut part_chunks[idx as usize], h, &wo)?;
+        }
 
-            for _ in 0..count {
-                part_chunks.push(tempfile::tempfile().unwrap());
+        let mut parts = Vec::with_capacity(part_chunks.len());
+
+        for mut it in part_chunks {
+            it.finish().phase(Phase::Split)?;
+
+            if it.stats.is_empty() {
+                it.remove().phase(Phase::Split)?;
+            } else {
+                it.close().phase(Phase::Split)?;
+                parts.push(it);
             }
+        }
 
-            let mut reader = BufReader::new(file);
+        Ok(parts)
+    }
 
-            loop {
-                let wo: Result<WordOffset, Box<ErrorKind>> = bincode::deserialize_from(&mut reader);
+    /// resplit big partitions until every one of them fits
+    ///
+    /// each level hashes with a new seed. a partition held by a few hot words
+    /// can't get smaller, it's marked hot and left for streaming aggregation.
+    fn split_big_chunks(&mut self) -> Result<(), Error> {
+        while let Some(mut partition) = self.big_chunks.pop() {
+            if self.cancel.is_cancelled() {
+                self.big_chunks.push(partition);
 
-                match wo {
-                    Ok(wo) => {
-                        let h = hash(&wo.0);
-                        let idx = h % count;
+                // resumed with the partitions still to split
+                self.save(Stage::Resplit)?;
 
-                        let mut chunk = &part_chunks[idx as usize];
-                        bincode::serialize_into(&mut chunk, &wo);
-                    }
-                    Err(_) => {
-                        break;
-                    }
+                return Err(Error::new(ErrorKind::Cancelled).with_phase(Phase::Split));
+            }
+
+            if partition.stats.distinct() <= HOT_KEYS {
+                partition.hot = true;
+                self.chunks.push(partition);
+                continue;
+            }
+
+            let count = match self.resplit_count(&partition) {
+                Some(count) => count,
+                None => {
+                    // out of files, it's counted as is and takes more memory
+                    self.chunks.push(partition);
+                    continue;
+                }
+            };
+
+            let records = partition.stats.records;
+            let path = partition.path.clone();
+
+            for mut part in self.resplit(partition, count)? {
+                if part.stats.records == records {
+                    // every record hashed to the same part, no progress
+                    part.hot = true;
+                    self.chunks.push(part);
+                } else if part.stats.bytes > self.fanout.threshold() {
+                    self.big_chunks.push(part);
+                } else {
+                    self.chunks.push(part);
                 }
             }
 
-            chunks.append(&mut part_chunks);
+            // parts replace the partition only once they are recorded
+            self.save(Stage::Resplit)?;
+
+            if let Some(path) = path {
+                remove_file(path).phase(Phase::Split)?;
+            }
         }
 
-        chunks.iter()
-            .for_each(|mut it| {
-                it.seek(SeekFrom::Start((0)));
-            });
+        Ok(())
+    }
 
-        while let Some(file) = chunks.pop() {
-            let metadata = file.metadata().unwrap();
-            if metadata.len() > CHUNK_THRESHOLD {
-                self.big_chunks.push(file)
-            } else {
-                self.chunks.push(file)
+    /// read the source once, writing every word into a partition by hash
+    fn scan(&mut self) -> Result<(), Error> {
+        if self.chunks.is_empty() {
+            for _ in 0..self.initial {
+                let part = self.create_partition()?;
+                self.chunks.push(part);
             }
         }
-    }
 
-    pub fn split(&mut self) -> Result<(), Error> {
         loop {
-            let line = self.inner.next_word();
+            let line = self.inner.next_token();
 
             match line {
-                Ok((line, offset)) => {
-                    let h = hash(&line);
-                    let idx = h % CHUNK_COUNT;
+                Ok((line, location)) => {
+                    // only between lines, the whole line is read again on resume
+                    if let Some(start) = self.inner.line_start() {
+                        if self.state.is_some() && start.offset >= self.next_checkpoint {
+                            self.save(Stage::Scan { next: start })?;
+                            self.next_checkpoint = start.offset + self.state.as_ref().map_or(0, StateDir::interval);
+                        }
+                    }
 
-                    let wo = WordOffset(line, offset);
-                    let mut file = &self.chunks[idx as usize];
+                    let key = K::from_word(line);
+                    let h = hash(&key.key_bytes());
+                    let idx = h % self.chunks.len() as u64;
 
-                    let buf = bincode::serialized_size(&wo);
+                    let wo = WordOffset(key, location);
 
-                    bincode::serialize_into(&mut file, &wo);
+                    write_record(&mut self.chunks[idx as usize], h, &wo)?;
                 }
 
                 Err(ChunkError::NextChunk) => {
@@ -116,36 +258,278 @@ impl HashSplitFile
                     break;
                 }
 
-                Err(ChunkError::IoError) => panic!(),
+                Err(ChunkError::Cancelled(location)) => {
+                    // resumed from the word not written yet
+                    self.save(Stage::Scan { next: location })?;
+
+                    let err = Error::new(ErrorKind::Cancelled).with_offset(location.offset);
+
+                    return Err(err.with_phase(Phase::Split));
+                }
+
+                Err(ChunkError::Fatal(err)) => return Err(err),
             }
         }
 
-        self.chunks.iter()
-            .for_each(|mut it| {
-                it.seek(SeekFrom::Start((0)));
-            });
+        self.scanned = true;
+
+        Ok(())
+    }
+
+    pub fn split(&mut self) -> Result<(), Error> {
+        if !self.scanned {
+            self.scan()?;
+        }
+
+        // named ones are closed until they're read, leaving fds to resplits
+        for it in self.chunks.iter_mut() {
+            it.finish().phase(Phase::Split)?;
+            it.close().phase(Phase::Split)?;
+        }
 
         let mut chunks = Vec::new();
 
-        while let Some(file) = self.chunks.pop() {
-            let metadata = file.metadata().unwrap();
-            if metadata.len() > CHUNK_THRESHOLD {
-                self.big_chunks.push(file)
+        while let Some(partition) = self.chunks.pop() {
+            if partition.stats.is_empty() {
+                partition.remove().phase(Phase::Split)?;
+            } else if partition.stats.bytes > self.fanout.threshold() && !partition.hot {
+                self.big_chunks.push(partition)
             } else {
-                chunks.push(file)
+                chunks.push(partition)
             }
         }
 
         self.chunks.append(&mut chunks);
 
+        self.save(Stage::Resplit)?;
+
         if !self.big_chunks.is_empty() {
-            self.split_big_chunks();
+            self.split_big_chunks()?;
         }
 
         Ok(())
     }
 
-    pub fn finish(self) -> Vec<File> {
-        self.chunks
+    /// partitions with their statistics, empty partitions are dropped
+    ///
+    /// after a cancelled split, it's what is written so far.
+    pub fn finish(self) -> Manifest {
+        let partitions = self.chunks.into_iter()
+            .chain(self.big_chunks)
+            .filter(|it| !it.stats.is_empty())
+            .collect();
+
+        Manifest::new(partitions)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{HashSplitFile, HOT_KEYS};
+    use crate::cancel::CancelToken;
+    use crate::v1::io::{Location, ReadOptions};
+    use crate::v2::checkpoint::{Stage, StateDir};
+    use crate::v2::count::Counter;
+    use crate::v2::fanout::FanOut;
+    use std::io::Write;
+
+    fn split(content: &[u8], fanout: FanOut) -> crate::v2::manifest::Manifest {
+        let mut tmp = tempfile::NamedTempFile::new().unwrap();
+        tmp.write_all(content).unwrap();
+
+        let mut spliter: HashSplitFile = HashSplitFile::new(tmp.path(), &ReadOptions::default(), fanout).unwrap();
+        spliter.split().unwrap();
+
+        spliter.finish()
+    }
+
+    #[test]
+    fn test_resplit() {
+        // 64 byte threshold and two-way splits need several levels, named
+        // partitions are closed so three files are enough
+        let dir = tempfile::tempdir().unwrap();
+        let fanout = FanOut::new(8 * 64, 3);
+
+        let mut tmp = tempfile::NamedTempFile::new().unwrap();
+        for i in 0..2000 {
+            writeln!(tmp, "w{}", i % 1000).unwrap();
+        }
+        writeln!(tmp, "last").unwrap();
+
+        let state = StateDir::open(dir.path(), "input".into(), 1 << 20).unwrap();
+        let mut spliter: HashSplitFile = HashSplitFile::new(tmp.path(), &ReadOptions::default(), fanout)
+            .unwrap()
+            .with_state(state);
+        spliter.split().unwrap();
+
+        let manifest = spliter.finish();
+        assert_eq!(manifest.records(), 2001);
+        assert!(manifest.partitions.iter().any(|it| it.level > 1));
+
+        for it in manifest.partitions.iter() {
+            assert!(it.hot || it.stats.bytes <= fanout.threshold());
+        }
+
+        let mut counter: Counter = Counter::new(manifest).unwrap();
+        counter.run().unwrap();
+
+        // two rounds of 10 three-byte, 90 four-byte and 900 five-byte lines
+        assert_eq!(counter.finish(), Some(("last".into(), Location::new(9780, 2001))));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_fd_budget() {
+        let fanout = FanOut::new(8 * 64, 8);
+
+        let mut content = Vec::new();
+        for i in 0..3000 {
+            writeln!(content, "w{}", i % 1500).unwrap();
+        }
+        writeln!(content, "last").unwrap();
+
+        let mut tmp = tempfile::NamedTempFile::new().unwrap();
+        tmp.write_all(&content).unwrap();
+
+        let mut spliter: HashSplitFile = HashSplitFile::new(tmp.path(), &ReadOptions::default(), fanout).unwrap();
+        spliter.scan().unwrap();
+        assert_eq!(spliter.chunks.len(), 8);
+
+        // seven other partitions are open, one file is left for a resplit
+        let big = spliter.chunks.pop().unwrap();
+        assert_eq!(spliter.resplit_count(&big), None);
+        spliter.chunks.push(big);
+
+        spliter.split().unwrap();
+
+        // too big partitions are kept instead of failing with too many open files
+        let manifest = spliter.finish();
+        assert_eq!(manifest.partitions.len(), 8);
+        assert!(manifest.partitions.iter().any(|it| it.stats.bytes > fanout.threshold()));
+
+        let mut counter: Counter = Counter::new(manifest).unwrap();
+        counter.run().unwrap();
+
+        assert_eq!(counter.finish().unwrap().0, "last");
+    }
+
+    #[test]
+    fn test_hot_key() {
+        let fanout = FanOut::new(8 * 64, 2);
+
+        let mut content = b"first\n".to_vec();
+        for _ in 0..1000 {
+            content.extend_from_slice(b"hot\n");
+        }
+        content.extend_from_slice(b"last\n");
+
+        let manifest = split(&content, fanout);
+        assert_eq!(manifest.records(), 1002);
+
+        let hot = manifest.partitions.iter().find(|it| it.hot).unwrap();
+        assert!(hot.stats.distinct() <= HOT_KEYS);
+
+        let mut counter: Counter = Counter::new(manifest).unwrap();
+        counter.run().unwrap();
+
+        assert_eq!(counter.finish(), Some(("first".into(), Location::new(0, 1))));
+    }
+
+    #[test]
+    fn test_resume() {
+        let dir = tempfile::tempdir().unwrap();
+        let fanout = FanOut::new(8 * 1024, 4);
+
+        let mut tmp = tempfile::NamedTempFile::new().unwrap();
+        for i in 0..100 {
+            writeln!(tmp, "w{}", i % 50).unwrap();
+        }
+
+        // scan the first half, then stop as if killed
+        {
+            let state = StateDir::open(dir.path(), "input".into(), 64).unwrap();
+            let mut spliter: HashSplitFile = HashSplitFile::new(tmp.path(), &ReadOptions::default(), fanout)
+                .unwrap()
+                .with_state(state);
+
+            spliter.scan().unwrap();
+        }
+
+        for i in 100..200 {
+            writeln!(tmp, "w{}", i % 50 + if i == 150 { 1000 } else { 0 }).unwrap();
+        }
+
+        let mut state = StateDir::open(dir.path(), "input".into(), 64).unwrap();
+        let (stage, partitions) = state.load::<String>().unwrap().unwrap();
+
+        let next = match &stage {
+            Stage::Scan { next } => *next,
+            _ => panic!("wrong stage"),
+        };
+        assert!(next.offset > 0 && next.line > 1);
+
+        let mut spliter: HashSplitFile = HashSplitFile::new(tmp.path(), &ReadOptions::default(), fanout)
+            .unwrap()
+            .with_state(state.clone());
+
+        spliter.resume(stage, partitions).unwrap();
+        spliter.split().unwrap();
+
+        let manifest = spliter.finish();
+        assert_eq!(manifest.records(), 200);
+
+        let mut counter: Counter = Counter::new(manifest).unwrap().with_state(state, Vec::new());
+        counter.run().unwrap();
+
+        // three rounds of 10 three-byte and 40 four-byte lines before it
+        assert_eq!(counter.finish(), Some(("w1000".into(), Location::new(570, 151))));
+
+        // partition files and checkpoint are removed
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let dir = tempfile::tempdir().unwrap();
+        let fanout = FanOut::new(8 * 1024, 4);
+
+        let mut tmp = tempfile::NamedTempFile::new().unwrap();
+        tmp.write_all(b"qwer\nabcd\nqwer\n").unwrap();
+
+        let options = ReadOptions::default();
+        options.cancel.cancel();
+
+        let state = StateDir::open(dir.path(), "input".into(), 64).unwrap();
+        let mut spliter: HashSplitFile = HashSplitFile::new(tmp.path(), &options, fanout)
+            .unwrap()
+            .with_state(state);
+
+        let err = spliter.split().unwrap_err();
+        assert!(err.is_cancelled());
+        assert_eq!(spliter.finish().records(), 0);
+
+        // a checkpoint is saved before the first word
+        let mut state = StateDir::open(dir.path(), "input".into(), 64).unwrap();
+        let (stage, partitions) = state.load::<String>().unwrap().unwrap();
+
+        match &stage {
+            Stage::Scan { next } => assert_eq!(*next, Location::new(0, 1)),
+            _ => panic!("wrong stage"),
+        }
+
+        let options = ReadOptions {
+            cancel: CancelToken::new(),
+            ..options
+        };
+        let mut spliter: HashSplitFile = HashSplitFile::new(tmp.path(), &options, fanout)
+            .unwrap()
+            .with_state(state);
+
+        spliter.resume(stage, partitions).unwrap();
+        spliter.split().unwrap();
+
+        let mut counter: Counter = Counter::new(spliter.finish()).unwrap();
+        counter.run().unwrap();
+
+        assert_eq!(counter.finish(), Some(("abcd".into(), Location::new(5, 2))));
+    }
+}
@@ -0,0 +1,348 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::spill;
+
+/// bits of hash used to pick a register
+const SKETCH_BITS: u32 = 8;
+const SKETCH_REGISTERS: usize = 1 << SKETCH_BITS;
+
+/// HyperLogLog with 256 registers, about 6.5% standard error
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DistinctSketch {
+    registers: Vec<u8>,
+}
+
+impl DistinctSketch {
+    pub fn new() -> Self {
+        DistinctSketch {
+            registers: vec![0; SKETCH_REGISTERS],
+        }
+    }
+
+    pub fn insert(&mut self, hash: u64) {
+        let idx = (hash >> (64 - SKETCH_BITS)) as usize;
+        // sentinel bit bounds the rank when remaining bits are all zero
+        let rest = (hash << SKETCH_BITS) | (1 << (SKETCH_BITS - 1));
+        let rank = rest.leading_zeros() as u8 + 1;
+
+        if rank > self.registers[idx] {
+            self.registers[idx] = rank;
+        }
+    }
+
+    pub fn estimate(&self) -> u64 {
+        let m = SKETCH_REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+
+        let sum: f64 = self.registers.iter().map(|&r| 2f64.powi(-(r as i32))).sum();
+        let raw = alpha * m * m / sum;
+
+        let zeros = self.registers.iter().filter(|&&r| r == 0).count();
+
+        // small range correction by linear counting
+        if raw <= 2.5 * m && zeros > 0 {
+            (m * (m / zeros as f64).ln()).round() as u64
+        } else {
+            raw.round() as u64
+        }
+    }
+}
+
+impl Default for DistinctSketch {
+    fn default() -> Self {
+        DistinctSketch::new()
+    }
+}
+
+/// statistics of records written into one partition
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PartitionStats {
+    pub records: u64,
+    pub bytes: u64,
+    pub min_offset: u64,
+    pub max_offset: u64,
+
+    sketch: DistinctSketch,
+}
+
+impl PartitionStats {
+    pub fn new() -> Self {
+        PartitionStats::default()
+    }
+
+    /// record a word by its hash, source offset and serialized size
+    pub fn add(&mut self, hash: u64, offset: u64, size: u64) {
+        if self.records == 0 {
+            self.min_offset = offset;
+            self.max_offset = offset;
+        } else {
+            self.min_offset = self.min_offset.min(offset);
+            self.max_offset = self.max_offset.max(offset);
+        }
+
+        self.records += 1;
+        self.bytes += size;
+        self.sketch.insert(hash);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records == 0
+    }
+
+    /// rough distinct word count, never more than records
+    pub fn distinct(&self) -> u64 {
+        self.sketch.estimate().min(self.records)
+    }
+}
+
+/// a partition file with its statistics
+pub struct Partition {
+    /// `None` once a named partition is closed
+    file: Option<File>,
+    /// only set for named files in a state directory, anonymous otherwise
+    pub path: Option<PathBuf>,
+    pub stats: PartitionStats,
+
+    /// too big to fit but held by a few words, counted by streaming aggregation
+    pub hot: bool,
+    /// times it's been split, resplit uses `level + 1` as hash seed
+    pub level: u32,
+}
+
+impl Partition {
+    /// new temp file with header reserved
+    pub fn create() -> Result<Self, Error> {
+        Partition::with_file(tempfile::tempfile()?, None)
+    }
+
+    /// new named file at `path`, it's not removed on drop
+    pub fn create_at(path: &Path) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+
+        Partition::with_file(file, Some(path.to_owned()))
+    }
+
+    fn with_file(mut file: File, path: Option<PathBuf>) -> Result<Self, Error> {
+        spill::begin(&mut file)?;
+        spill::charge(spill::HEADER_SIZE)?;
+
+        Ok(Partition {
+            file: Some(file),
+            path,
+            stats: PartitionStats::new(),
+            hot: false,
+            level: 0,
+        })
+    }
+
+    /// named partition loaded from a checkpoint, closed if `file` is `None`
+    pub fn restore(file: Option<File>, path: PathBuf, stats: PartitionStats, hot: bool, level: u32) -> Self {
+        Partition {
+            file,
+            path: Some(path),
+            stats,
+            hot,
+            level,
+        }
+    }
+
+    /// file records are written into, an error once it's closed
+    pub fn writer(&mut self) -> Result<&mut File, Error> {
+        match (&mut self.file, &self.path) {
+            (Some(file), _) => Ok(file),
+            (None, Some(path)) => Err(Error::from(io::Error::other("write to a closed partition")).with_file(path)),
+            (None, None) => Err(closed().into()),
+        }
+    }
+
+    /// size of the file
+    pub fn len(&self) -> io::Result<u64> {
+        match (&self.file, &self.path) {
+            (Some(file), _) => Ok(file.metadata()?.len()),
+            (None, Some(path)) => Ok(fs::metadata(path)?.len()),
+            (None, None) => Err(closed()),
+        }
+    }
+
+    /// open file, `None` once it's closed
+    pub fn file(&self) -> Option<&File> {
+        self.file.as_ref()
+    }
+
+    /// close a finished named partition so it doesn't hold a file descriptor
+    /// until it's read, it's synced first
+    ///
+    /// an anonymous one can't be reopened, it stays open.
+    pub fn close(&mut self) -> io::Result<()> {
+        if self.path.is_none() {
+            return Ok(());
+        }
+
+        if let Some(file) = self.file.take() {
+            file.sync_data()?;
+        }
+
+        Ok(())
+    }
+
+    /// file to read the records from, reopened at the start if it's closed
+    pub fn into_file(self) -> io::Result<File> {
+        match (self.file, self.path) {
+            (Some(file), _) => Ok(file),
+            (None, Some(path)) => File::open(path),
+            (None, None) => Err(closed()),
+        }
+    }
+
+    /// delete the file if it's named
+    pub fn remove(self) -> Result<(), Error> {
+        match self.path {
+            Some(path) => remove_file(path),
+            None => Ok(()),
+        }
+    }
+
+    /// record count into header, and rewind to start for reading
+    ///
+    /// a closed partition is finished already.
+    pub fn finish(&mut self) -> Result<(), Error> {
+        match &mut self.file {
+            Some(file) => spill::finish(file, self.stats.records),
+            None => Ok(()),
+        }
+    }
+}
+
+/// anonymous partitions are never closed, they couldn't be found again
+fn closed() -> io::Error {
+    io::Error::other("anonymous partition is closed")
+}
+
+/// delete a named partition file
+pub fn remove_file(path: PathBuf) -> Result<(), Error> {
+    fs::remove_file(&path).map_err(|err| Error::from(err).with_file(path))
+}
+
+/// all partitions produced by a split
+pub struct Manifest {
+    pub partitions: Vec<Partition>,
+}
+
+impl Manifest {
+    pub fn new(partitions: Vec<Partition>) -> Self {
+        Manifest { partitions }
+    }
+
+    pub fn records(&self) -> u64 {
+        self.partitions.iter().map(|it| it.stats.records).sum()
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.partitions.iter().map(|it| it.stats.bytes).sum()
+    }
+
+    /// largest partition size over mean partition size, 1.0 means no skew
+    pub fn skew(&self) -> f64 {
+        let max = self.partitions.iter().map(|it| it.stats.bytes).max().unwrap_or(0);
+
+        if max == 0 {
+            return 1.0;
+        }
+
+        let mean = self.bytes() as f64 / self.partitions.len() as f64;
+
+        max as f64 / mean
+    }
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} partitions, {} records, {} bytes, skew {:.2}",
+            self.partitions.len(),
+            self.records(),
+            self.bytes(),
+            self.skew()
+        )?;
+
+        for (idx, it) in self.partitions.iter().enumerate() {
+            writeln!(
+                f,
+                "  #{:<4} records {:>12} bytes {:>14} distinct ~{:>12} offset {}..={}{}",
+                idx,
+                it.stats.records,
+                it.stats.bytes,
+                it.stats.distinct(),
+                it.stats.min_offset,
+                it.stats.max_offset,
+                if it.hot { " hot" } else { "" }
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DistinctSketch, Partition, PartitionStats};
+    use crate::v2::utils::hash;
+
+    #[test]
+    fn test_sketch() {
+        let mut sketch = DistinctSketch::new();
+        assert_eq!(sketch.estimate(), 0);
+
+        for i in 0..10000u64 {
+            sketch.insert(hash(&(i % 5000u64).to_le_bytes()));
+        }
+
+        let estimate = sketch.estimate() as f64;
+        assert!((estimate - 5000.0).abs() < 5000.0 * 0.2, "{}", estimate);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut stats = PartitionStats::new();
+        assert!(stats.is_empty());
+
+        stats.add(hash(b"qwer"), 10, 20);
+        stats.add(hash(b"abcd"), 5, 20);
+        stats.add(hash(b"qwer"), 15, 20);
+
+        assert_eq!(stats.records, 3);
+        assert_eq!(stats.bytes, 60);
+        assert_eq!(stats.min_offset, 5);
+        assert_eq!(stats.max_offset, 15);
+        assert_eq!(stats.distinct(), 2);
+    }
+
+    #[test]
+    fn test_closed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("part-0");
+
+        let mut partition = Partition::create_at(&path).unwrap();
+        assert!(partition.writer().is_ok());
+
+        partition.finish().unwrap();
+        partition.close().unwrap();
+
+        // an error rather than a panic, and the records are still there to read
+        let err = partition.writer().unwrap_err();
+        assert!(err.to_string().contains("part-0"), "{}", err);
+        assert!(partition.into_file().is_ok());
+    }
+}
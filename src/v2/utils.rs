@@ -1,10 +1,171 @@
-use std::hash::{Hash, Hasher};
-use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
 
-pub fn hash<T: Hash>(t: &T) -> u64 {
-    let mut hasher = DefaultHasher::new();
+/// version of the hash below, it's part of a checkpoint's settings since
+/// partitions and fingerprints saved there depend on it
+pub const HASH_VERSION: u32 = 2;
 
-    t.hash(&mut hasher);
+/// SipHash-1-3 written out here, unlike `DefaultHasher` its algorithm can't
+/// change with the Rust release. integers are fed little endian.
+#[derive(Clone, Debug)]
+pub struct SipHasher {
+    v0: u64,
+    v1: u64,
+    v2: u64,
+    v3: u64,
+    /// bytes not compressed yet, little endian
+    tail: u64,
+    ntail: usize,
+    length: u64,
+}
+
+impl SipHasher {
+    pub fn new_with_keys(k0: u64, k1: u64) -> Self {
+        SipHasher {
+            v0: k0 ^ 0x736f_6d65_7073_6575,
+            v1: k1 ^ 0x646f_7261_6e64_6f6d,
+            v2: k0 ^ 0x6c79_6765_6e65_7261,
+            v3: k1 ^ 0x7465_6462_7974_6573,
+            tail: 0,
+            ntail: 0,
+            length: 0,
+        }
+    }
+
+    fn round(&mut self) {
+        self.v0 = self.v0.wrapping_add(self.v1);
+        self.v1 = self.v1.rotate_left(13) ^ self.v0;
+        self.v0 = self.v0.rotate_left(32);
+        self.v2 = self.v2.wrapping_add(self.v3);
+        self.v3 = self.v3.rotate_left(16) ^ self.v2;
+        self.v0 = self.v0.wrapping_add(self.v3);
+        self.v3 = self.v3.rotate_left(21) ^ self.v0;
+        self.v2 = self.v2.wrapping_add(self.v1);
+        self.v1 = self.v1.rotate_left(17) ^ self.v2;
+        self.v2 = self.v2.rotate_left(32);
+    }
+
+    fn compress(&mut self, m: u64) {
+        self.v3 ^= m;
+        self.round();
+        self.v0 ^= m;
+    }
+}
+
+impl Hasher for SipHasher {
+    fn write(&mut self, mut bytes: &[u8]) {
+        self.length += bytes.len() as u64;
+
+        if self.ntail > 0 {
+            let take = (8 - self.ntail).min(bytes.len());
+
+            for (idx, byte) in bytes[..take].iter().enumerate() {
+                self.tail |= (*byte as u64) << (8 * (self.ntail + idx));
+            }
+
+            self.ntail += take;
+            bytes = &bytes[take..];
+
+            if self.ntail < 8 {
+                return;
+            }
+
+            self.compress(self.tail);
+            self.tail = 0;
+            self.ntail = 0;
+        }
+
+        let mut words = bytes.chunks_exact(8);
+
+        for word in &mut words {
+            let mut m = [0u8; 8];
+            m.copy_from_slice(word);
+
+            self.compress(u64::from_le_bytes(m));
+        }
+
+        for (idx, byte) in words.remainder().iter().enumerate() {
+            self.tail |= (*byte as u64) << (8 * idx);
+        }
+
+        self.ntail = words.remainder().len();
+    }
+
+    fn write_u8(&mut self, i: u8) {
+        self.write(&[i]);
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes());
+    }
+
+    /// as u64, so 32-bit builds hash lengths the same
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        let mut state = self.clone();
+        let b = ((self.length & 0xff) << 56) | self.tail;
+
+        state.compress(b);
+        state.v2 ^= 0xff;
+
+        for _ in 0..3 {
+            state.round();
+        }
+
+        state.v0 ^ state.v1 ^ state.v2 ^ state.v3
+    }
+}
+
+/// hash of raw bytes, a key hashes its [WordKey::key_bytes](crate::key::WordKey::key_bytes)
+pub fn hash(bytes: &[u8]) -> u64 {
+    hash_with_seed(bytes, 0)
+}
+
+/// hash with a seed, partitions split again use a different seed so that
+/// records don't land in the same sub partition
+///
+/// bytes are fed as they are, not through a `Hash` impl, whose extra bytes
+/// like a length prefix are up to the standard library.
+pub fn hash_with_seed(bytes: &[u8], seed: u64) -> u64 {
+    let mut hasher = SipHasher::new_with_keys(seed, 0);
+
+    hasher.write(bytes);
 
     hasher.finish()
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::{hash, hash_with_seed, SipHasher};
+    use std::hash::Hasher;
+
+    #[test]
+    fn test_sip_hasher() {
+        let bytes: Vec<u8> = (0..64).collect();
+
+        // fed in pieces or at once, it's the same
+        let mut whole = SipHasher::new_with_keys(1, 2);
+        whole.write(&bytes);
+
+        let mut pieces = SipHasher::new_with_keys(1, 2);
+        for piece in bytes.chunks(3) {
+            pieces.write(piece);
+        }
+
+        assert_eq!(whole.finish(), pieces.finish());
+
+        // values must never change, they're saved in checkpoints
+        assert_eq!(hash(b"qwer"), 0xba07_ac1e_0655_7887);
+        assert_ne!(hash(b"qwer"), hash_with_seed(b"qwer", 1));
+    }
+}